@@ -1,101 +1,66 @@
 use canbench_rs::{bench, bench_fn, BenchResult};
+use ic_dev_kit_rs::candle::{AutoregressiveModel, CandleModel};
+use ic_dev_kit_rs::text_generation::GenerationConfig;
 
-// Mock the model initialization for benchmarking
-// In real benchmarks, you'd have the actual model loaded
+use crate::qwen3::Qwen3Model;
 
-#[bench(raw)]
-fn bench_single_token_generation() -> BenchResult {
-    bench_fn(|| {
-        // This would call your actual inference
-        // For now, we'll measure a representative workload
-        let mut sum = 0u64;
-        for i in 0..1000 {
-            sum = sum.wrapping_add(i);
-        }
-        sum
-    })
+const PROMPT: &str = "Once upon a time in a land far far away";
+
+/// Tiny random-weight model from `tests/fixtures/gen_fixtures.py`.
+fn fixture_model() -> Qwen3Model {
+    let weights = include_bytes!("../tests/fixtures/model.gguf").to_vec();
+    let tokenizer = include_bytes!("../tests/fixtures/tokenizer.json").to_vec();
+    Qwen3Model::load(weights, Some(tokenizer)).expect("fixture model loads")
 }
 
-#[bench(raw)]
-fn bench_10_token_generation() -> BenchResult {
-    bench_fn(|| {
-        let mut sum = 0u64;
-        for i in 0..10000 {
-            sum = sum.wrapping_add(i);
-        }
-        sum
-    })
+fn greedy_config() -> GenerationConfig {
+    GenerationConfig {
+        temperature: 0.,
+        ..GenerationConfig::default()
+    }
 }
 
 #[bench(raw)]
-fn bench_100_token_generation() -> BenchResult {
+fn bench_tokenization() -> BenchResult {
+    let model = fixture_model();
+    let tokenizer = model.get_tokenizer();
+    let text = PROMPT.repeat(10);
+
     bench_fn(|| {
-        let mut sum = 0u64;
-        for i in 0..100000 {
-            sum = sum.wrapping_add(i);
-        }
-        sum
+        tokenizer.encode(&text).unwrap();
     })
 }
 
-// Memory allocation benchmarks
+// Prefill: encoding plus the forward pass over the whole prompt
 #[bench(raw)]
-fn bench_model_weight_allocation() -> BenchResult {
+fn bench_prefill() -> BenchResult {
+    let mut model = fixture_model();
+    let tokenizer = model.get_tokenizer();
+    let config = greedy_config();
+
     bench_fn(|| {
-        // Simulate allocating space for Q8_0 Qwen-0.6B (~600MB)
-        let size = 600 * 1024 * 1024 / 1000; // Smaller for benchmark
-        let _vec: Vec<u8> = vec![0; size];
+        model.init_generation(PROMPT.to_string(), &*tokenizer, &config).unwrap();
     })
 }
 
-#[bench(raw)]
-fn bench_tokenization() -> BenchResult {
+fn bench_decode(steps: usize) -> BenchResult {
+    let mut model = fixture_model();
+    let tokenizer = model.get_tokenizer();
+    model.init_generation(PROMPT.to_string(), &*tokenizer, &greedy_config()).unwrap();
+
     bench_fn(|| {
-        // Simulate tokenization workload
-        let text = "Once upon a time in a land far far away".repeat(10);
-        let _tokens: Vec<u32> = text
-            .split_whitespace()
-            .map(|_| 1234u32) // Mock token
-            .collect();
+        for _ in 0..steps {
+            model.generate_next_token(&*tokenizer).unwrap();
+        }
     })
 }
 
 #[bench(raw)]
-fn bench_forward_pass_simulation() -> BenchResult {
-    bench_fn(|| {
-        // Simulate matrix operations in forward pass
-        let size = 100;
-        let mut matrix = vec![vec![0.0f32; size]; size];
-        
-        for i in 0..size {
-            for j in 0..size {
-                matrix[i][j] = (i as f32 * j as f32).sin();
-            }
-        }
-        
-        // Simulate some compute
-        let mut result = 0.0f32;
-        for row in &matrix {
-            for &val in row {
-                result += val;
-            }
-        }
-        result
-    })
+fn bench_1_token_decode() -> BenchResult {
+    bench_decode(1)
 }
 
-// KV cache benchmarks
 #[bench(raw)]
-fn bench_kv_cache_update() -> BenchResult {
-    bench_fn(|| {
-        // Simulate updating KV cache
-        let cache_size = 1024;
-        let hidden_dim = 512;
-        let mut cache: Vec<Vec<f32>> = vec![vec![0.0; hidden_dim]; cache_size];
-        
-        // Update cache
-        for i in 0..10 {
-            cache[i] = vec![i as f32; hidden_dim];
-        }
-    })
+fn bench_10_token_decode() -> BenchResult {
+    bench_decode(10)
 }
@@ -0,0 +1,63 @@
+// Storage hot paths on the upload critical path. Included from `storage.rs`
+// so the benches can reach its private buffers and endpoints.
+
+use canbench_rs::{bench, bench_fn, BenchResult};
+
+use super::*;
+
+const KEY: &str = "__bench_weights__";
+
+/// Fills `BUFFER_MAP` with `count` chunks of `size` bytes (IDs `0..count`).
+fn populate_chunks(count: u32, size: usize) {
+    BUFFER_MAP.with(|m| {
+        let mut m = m.borrow_mut();
+        m.clear();
+        for id in 0..count {
+            m.insert(id, Rc::new(vec![id as u8; size]));
+        }
+    });
+}
+
+fn bench_consolidate(count: u32, size: usize) -> BenchResult {
+    populate_chunks(count, size);
+    bench_fn(|| {
+        consolidate_parallel_chunks(count, None).unwrap();
+    })
+}
+
+fn bench_save_parallel(count: u32, size: usize) -> BenchResult {
+    populate_chunks(count, size);
+    bench_fn(|| {
+        save_parallel_to_stable(KEY.to_string(), count, None, true, None).unwrap();
+    })
+}
+
+// A few large chunks, as a real weights upload sends them
+#[bench(raw)]
+fn bench_consolidate_16_x_1mib() -> BenchResult {
+    bench_consolidate(16, 1 << 20)
+}
+
+// Thousands of small chunks, to catch per-chunk HashMap iteration and sort costs
+#[bench(raw)]
+fn bench_consolidate_4096_x_1kib() -> BenchResult {
+    bench_consolidate(4096, 1 << 10)
+}
+
+#[bench(raw)]
+fn bench_save_parallel_16_x_1mib() -> BenchResult {
+    bench_save_parallel(16, 1 << 20)
+}
+
+#[bench(raw)]
+fn bench_save_parallel_4096_x_1kib() -> BenchResult {
+    bench_save_parallel(4096, 1 << 10)
+}
+
+#[bench(raw)]
+fn bench_load_16mib() -> BenchResult {
+    write_stable(KEY.to_string(), vec![7; 16 << 20], Compression::None, None);
+    bench_fn(|| {
+        load_from_stable(KEY.to_string()).unwrap();
+    })
+}
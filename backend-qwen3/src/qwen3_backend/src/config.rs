@@ -0,0 +1,226 @@
+//! Validated construction of ic-dev-kit's `GenerationConfig` and the local `SamplingOptions`
+
+use ic_dev_kit_rs::text_generation::GenerationConfig;
+
+use crate::sampling::{GenerateOptions, MirostatConfig, SamplingOptions};
+
+/// Checks that every field is in a range the sampler honours as written,
+/// rather than silently coercing it.
+pub fn validate(config: &GenerationConfig) -> Result<(), String> {
+    if !config.temperature.is_finite() || config.temperature < 0. {
+        return Err(format!("temperature must be >= 0, got {}", config.temperature));
+    }
+    if !(config.top_p > 0. && config.top_p <= 1.) {
+        return Err(format!("top_p must be in (0, 1], got {}", config.top_p));
+    }
+    if !config.repeat_penalty.is_finite() || config.repeat_penalty < 1. {
+        return Err(format!("repeat_penalty must be >= 1, got {}", config.repeat_penalty));
+    }
+    if config.max_tokens == 0 {
+        return Err("max_tokens must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+/// Lets front-ends check settings without running inference; generation
+/// applies the same checks.
+#[ic_cdk::query]
+fn validate_generation_config(config: GenerationConfig) -> Result<(), String> {
+    validate(&config)
+}
+
+/// Chained setters over the built-in defaults; `build` validates. The
+/// `SamplingOptions` setters only affect `build_options`.
+pub struct GenerationConfigBuilder {
+    config: GenerationConfig,
+    sampling: SamplingOptions,
+}
+
+impl Default for GenerationConfigBuilder {
+    fn default() -> Self {
+        Self {
+            config: GenerationConfig::default(),
+            sampling: SamplingOptions {
+                greedy: false,
+                truncate_prompt: false,
+                min_p: None,
+                typical_p: None,
+                random_seed: false,
+                echo: false,
+                mirostat: None,
+                min_tokens: 0,
+                json_mode: false,
+                max_output_bytes: None,
+                prepend_bos: false,
+                keep_special_tokens: false,
+                trim_trailing_partial: false,
+                max_instruction_fraction: None,
+                include_tokens: false,
+            },
+        }
+    }
+}
+
+// Routed through the builder so its defaults are the only ones
+impl Default for SamplingOptions {
+    fn default() -> Self {
+        GenerationConfigBuilder::new().sampling
+    }
+}
+
+impl GenerationConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.config.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.config.temperature = temperature;
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f64) -> Self {
+        self.config.top_p = top_p;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = seed;
+        self
+    }
+
+    pub fn repeat_penalty(mut self, repeat_penalty: f32) -> Self {
+        self.config.repeat_penalty = repeat_penalty;
+        self
+    }
+
+    pub fn repeat_last_n(mut self, repeat_last_n: usize) -> Self {
+        self.config.repeat_last_n = repeat_last_n;
+        self
+    }
+
+    pub fn greedy(mut self, greedy: bool) -> Self {
+        self.sampling.greedy = greedy;
+        self
+    }
+
+    pub fn truncate_prompt(mut self, truncate_prompt: bool) -> Self {
+        self.sampling.truncate_prompt = truncate_prompt;
+        self
+    }
+
+    pub fn min_p(mut self, min_p: f64) -> Self {
+        self.sampling.min_p = Some(min_p);
+        self
+    }
+
+    pub fn typical_p(mut self, typical_p: f64) -> Self {
+        self.sampling.typical_p = Some(typical_p);
+        self
+    }
+
+    pub fn random_seed(mut self, random_seed: bool) -> Self {
+        self.sampling.random_seed = random_seed;
+        self
+    }
+
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.sampling.echo = echo;
+        self
+    }
+
+    pub fn mirostat(mut self, tau: f32, eta: f32) -> Self {
+        self.sampling.mirostat = Some(MirostatConfig { tau, eta });
+        self
+    }
+
+    pub fn min_tokens(mut self, min_tokens: usize) -> Self {
+        self.sampling.min_tokens = min_tokens;
+        self
+    }
+
+    pub fn json_mode(mut self, json_mode: bool) -> Self {
+        self.sampling.json_mode = json_mode;
+        self
+    }
+
+    pub fn max_output_bytes(mut self, max_output_bytes: usize) -> Self {
+        self.sampling.max_output_bytes = Some(max_output_bytes);
+        self
+    }
+
+    pub fn prepend_bos(mut self, prepend_bos: bool) -> Self {
+        self.sampling.prepend_bos = prepend_bos;
+        self
+    }
+
+    pub fn keep_special_tokens(mut self, keep_special_tokens: bool) -> Self {
+        self.sampling.keep_special_tokens = keep_special_tokens;
+        self
+    }
+
+    pub fn trim_trailing_partial(mut self, trim_trailing_partial: bool) -> Self {
+        self.sampling.trim_trailing_partial = trim_trailing_partial;
+        self
+    }
+
+    pub fn max_instruction_fraction(mut self, fraction: f64) -> Self {
+        self.sampling.max_instruction_fraction = Some(fraction);
+        self
+    }
+
+    pub fn include_tokens(mut self, include_tokens: bool) -> Self {
+        self.sampling.include_tokens = include_tokens;
+        self
+    }
+
+    pub fn build(self) -> Result<GenerationConfig, String> {
+        validate(&self.config)?;
+        Ok(self.config)
+    }
+
+    /// The config plus the sampling options, both validated.
+    pub fn build_options(self) -> Result<GenerateOptions, String> {
+        validate(&self.config)?;
+        self.sampling.validate()?;
+        Ok(GenerateOptions { config: self.config, sampling: self.sampling })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_defaults_are_valid() {
+        assert!(GenerationConfigBuilder::new().build().is_ok());
+        assert!(GenerationConfigBuilder::new().build_options().is_ok());
+        assert!(GenerationConfigBuilder::new().temperature(0.).top_p(1.).repeat_penalty(1.).build().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_config() {
+        let invalid = [
+            GenerationConfigBuilder::new().temperature(-0.5),
+            GenerationConfigBuilder::new().temperature(f64::INFINITY),
+            GenerationConfigBuilder::new().top_p(0.),
+            GenerationConfigBuilder::new().top_p(1.5),
+            GenerationConfigBuilder::new().repeat_penalty(0.9),
+            GenerationConfigBuilder::new().max_tokens(0),
+        ];
+        for builder in invalid {
+            assert!(builder.build().is_err());
+        }
+    }
+
+    #[test]
+    fn build_options_also_checks_sampling() {
+        assert!(GenerationConfigBuilder::new().min_p(2.).build().is_ok());
+        assert!(GenerationConfigBuilder::new().min_p(2.).build_options().is_err());
+        assert!(GenerationConfigBuilder::new().max_instruction_fraction(0.).build_options().is_err());
+    }
+}
@@ -0,0 +1,421 @@
+//! Generation loop shared by every endpoint that drives the model
+
+use candid::CandidType;
+use ic_dev_kit_rs::candle::AutoregressiveModel;
+use ic_dev_kit_rs::text_generation::InferenceRequest;
+use serde::Deserialize;
+
+use std::cell::Cell;
+
+use crate::qwen3::Qwen3Model;
+use crate::sampling::{GenerateOptions, SamplingOptions};
+use crate::with_model;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FinishReason {
+    /// The model emitted an EOS token
+    Eos,
+    /// `max_tokens` was reached
+    Length,
+    /// A caller-supplied stop sequence matched
+    Stop,
+    /// `request_cancel` / `cancel_current` was called
+    Cancelled,
+    /// Prompt plus output filled the model's context window
+    ContextFull,
+    /// The generated text reached `SamplingOptions::max_output_bytes`
+    MaxBytes,
+    /// Another decode step would likely exceed the instruction budget, or the
+    /// `max_instruction_fraction` share of it was used up
+    InstructionBudget,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Completion {
+    pub text: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub finish_reason: FinishReason,
+    /// Sampled token IDs, excluding the prompt; empty unless
+    /// `SamplingOptions::include_tokens` is set
+    pub generated_tokens: Vec<u32>,
+    /// As in `GenerationReport`
+    pub elapsed_ns: u64,
+    pub instructions: u64,
+    pub tokens_per_second: f64,
+    /// Effective seed; pass it back as `config.seed` (with `random_seed` off) to reproduce
+    pub seed: u64,
+}
+
+/// Outcome of `continue_prefill`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum PrefillStatus {
+    /// Ran out of instruction budget again; call `continue_prefill` once more
+    Partial { processed: usize, total: usize },
+    /// Prefill finished and the generation was decoded to completion
+    Done { completion: Completion },
+}
+
+/// A completion plus what a verifier needs to re-derive it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Replay {
+    pub completion: Completion,
+    pub generated_tokens: Vec<u32>,
+    /// Per generated token, see `Qwen3Model::set_capture_logprobs`
+    pub logprobs: Vec<f32>,
+    /// Seed actually used, after settings and `random_seed`
+    pub seed: u64,
+}
+
+thread_local! {
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks a generation as running for as long as it is held.
+///
+/// Messages execute one at a time, so a second call can't actually overlap a
+/// running one; the flag turns re-entry, e.g. from a future async path, into
+/// an immediate error.
+struct BusyGuard;
+
+impl BusyGuard {
+    fn acquire() -> Result<Self, String> {
+        if BUSY.with(|b| b.replace(true)) {
+            return Err("Busy: another generation is running, retry later".to_string());
+        }
+        Ok(Self)
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        BUSY.with(|b| b.set(false));
+    }
+}
+
+/// Runs `f` on the loaded model while holding the busy flag. Refusals before
+/// `f` runs (busy, no model loaded) are recorded in the metrics here; failures
+/// inside it are recorded where they happen.
+fn exclusive<R>(f: impl FnOnce(&mut Qwen3Model) -> Result<R, String>) -> Result<R, String> {
+    let _busy = BusyGuard::acquire().inspect_err(|e| crate::metrics::record_refusal(e))?;
+    let mut reached = false;
+    let result = with_model(|model| {
+        reached = true;
+        f(model)
+    });
+    if let (false, Err(e)) = (reached, &result) {
+        crate::metrics::record_refusal(e);
+    }
+    result
+}
+
+/// Runs a full completion on the loaded model. Generated text is cut at the
+/// first occurrence of any `stop` sequence.
+pub fn complete(prompt: String, options: &GenerateOptions, stop: &[String]) -> Result<Completion, String> {
+    exclusive(|model| complete_on(model, prompt, options, stop))
+}
+
+/// `complete` against a model that isn't (necessarily) the live one.
+pub fn complete_on(
+    model: &mut Qwen3Model,
+    prompt: String,
+    options: &GenerateOptions,
+    stop: &[String],
+) -> Result<Completion, String> {
+    let tokenizer = model.get_tokenizer();
+    let text = model.init_with_options(prompt, &*tokenizer, options)?;
+    // As processed: with BOS, after truncation
+    let prompt_tokens = model.prompt_len();
+    finish(model, text, prompt_tokens, stop)
+}
+
+/// Runs each request in turn on the shared model, all with the same `sampling`
+/// options. The caller is admitted (and rate-limited) once for the whole batch.
+/// Once the next request might not fit in the remaining budget (judged by the
+/// costliest one so far), the rest are failed without running.
+pub fn complete_batch(
+    requests: Vec<InferenceRequest>,
+    sampling: Option<SamplingOptions>,
+) -> Vec<Result<Completion, String>> {
+    if let Err(e) = crate::policy::check_generation_allowed() {
+        crate::metrics::record_refusal(&e);
+        return requests.iter().map(|_| Err(e.clone())).collect();
+    }
+    let mut results = Vec::with_capacity(requests.len());
+    let budget = crate::settings::cost_model().instruction_budget;
+    let mut costliest = 0;
+    for request in requests {
+        let start = ic_cdk::api::performance_counter(0);
+        if start + costliest > budget {
+            results.push(Err("Skipped: batch instruction budget exhausted".to_string()));
+            continue;
+        }
+
+        let options = GenerateOptions::resolve(request.config, sampling.clone());
+        results.push(crate::policy::run_admitted(|| complete(request.prompt, &options, &[])));
+        costliest = costliest.max(ic_cdk::api::performance_counter(0) - start);
+    }
+    results
+}
+
+/// Samples `request` `n` times from a fresh start each, for best-of-n and
+/// majority voting. Sample `i` uses `seeds[i]`, or `base + i` past the end of
+/// `seeds`, where `base` is `config.seed` or, with `random_seed`, one random
+/// seed drawn for the call. The caller is admitted (and rate-limited) once.
+/// Stops early, returning fewer samples, once the next might not fit in the
+/// remaining budget.
+pub fn complete_n(
+    request: InferenceRequest,
+    n: u32,
+    seeds: Option<Vec<u64>>,
+    sampling: Option<SamplingOptions>,
+) -> Vec<Result<Completion, String>> {
+    if let Err(e) = crate::policy::check_generation_allowed() {
+        crate::metrics::record_refusal(&e);
+        return vec![Err(e)];
+    }
+    let mut options = GenerateOptions::resolve(request.config, sampling);
+    let seeds = seeds.unwrap_or_default();
+    // `random_seed` mixes only the time and caller, both fixed within this
+    // message, so it is drawn once here and offset per sample instead
+    let base_seed = if options.sampling.random_seed {
+        options.sampling.random_seed = false;
+        crate::sampling::random_seed()
+    } else {
+        options.config.seed
+    };
+    let budget = crate::settings::cost_model().instruction_budget;
+    let mut costliest = 0;
+    let mut results = Vec::with_capacity(n as usize);
+    for i in 0..n as usize {
+        let start = ic_cdk::api::performance_counter(0);
+        if start + costliest > budget {
+            break;
+        }
+
+        options.config.seed = seeds.get(i).copied().unwrap_or_else(|| base_seed.wrapping_add(i as u64));
+        results.push(crate::policy::run_admitted(|| complete(request.prompt.clone(), &options, &[])));
+        costliest = costliest.max(ic_cdk::api::performance_counter(0) - start);
+    }
+    results
+}
+
+/// Runs `request` recording tokens and log-probabilities.
+///
+/// Replays are bit-exact for the same weights, tokenizer, request and seed,
+/// provided the inputs the request doesn't carry are also unchanged: stored
+/// defaults (used when the config or `sampling` is absent), `random_seed`
+/// (which makes the seed differ per call) and `max_repeat_last_n`. On-chain wasm is deterministic; off-chain
+/// multithreaded or GPU builds may differ in float reduction order, which can
+/// flip near-tied samples.
+pub fn replay(request: InferenceRequest, sampling: Option<SamplingOptions>) -> Result<Replay, String> {
+    let options = GenerateOptions::resolve(request.config, sampling);
+    exclusive(|model| {
+        let tokenizer = model.get_tokenizer();
+        model.set_capture_logprobs(true);
+        let result = model.init_with_options(request.prompt, &*tokenizer, &options).and_then(|text| {
+            let prompt_tokens = model.prompt_len();
+            finish(model, text, prompt_tokens, &[])
+        });
+        model.set_capture_logprobs(false);
+
+        Ok(Replay {
+            completion: result?,
+            generated_tokens: model.generation_report().generated_tokens,
+            logprobs: model.logprobs().to_vec(),
+            seed: model.seed(),
+        })
+    })
+}
+
+/// Re-samples the last prompt on the loaded model with `options`.
+pub fn regenerate(options: &GenerateOptions) -> Result<Completion, String> {
+    exclusive(|model| {
+        let text = model.init_regeneration(options)?;
+        let prompt_tokens = model.prompt_len();
+        finish(model, text, prompt_tokens, &[])
+    })
+}
+
+/// Like `complete`, but resumes from a cached prefix and only processes `tokens`.
+pub fn complete_with_prefix(
+    prefix_id: &str,
+    tokens: &[u32],
+    options: &GenerateOptions,
+    stop: &[String],
+) -> Result<Completion, String> {
+    exclusive(|model| {
+        let text = model.init_with_prefix(prefix_id, tokens, options)?;
+        finish(model, text, tokens.len(), stop)
+    })
+}
+
+/// Resumes a paused chunked prefill and, once it completes, decodes the rest
+/// of the generation as `complete` would. Stop sequences of the original
+/// request aren't kept across calls, so none apply.
+pub fn resume_prefill() -> Result<PrefillStatus, String> {
+    exclusive(|model| match model.continue_prefill() {
+        Ok(text) => {
+            let prompt_tokens = model.prompt_len();
+            Ok(PrefillStatus::Done { completion: finish(model, text, prompt_tokens, &[])? })
+        }
+        Err(e) => match model.prefill_progress() {
+            Some((processed, total)) => Ok(PrefillStatus::Partial { processed, total }),
+            None => Err(e),
+        },
+    })
+}
+
+/// Decodes until EOS, `max_tokens`, `max_output_bytes`, a stop sequence or the
+/// instruction budget, starting from the prefill's `text`.
+fn finish(
+    model: &mut Qwen3Model,
+    mut text: String,
+    prompt_tokens: usize,
+    stop: &[String],
+) -> Result<Completion, String> {
+    let tokenizer = model.get_tokenizer();
+    // Stop sequences only apply to generated text, not an echoed prompt
+    let echo_len = model.echo_len();
+    let max_bytes = model.max_output_bytes();
+    let cost = crate::settings::cost_model();
+    // Soft cap: as many tokens as fit in the chosen share of the budget
+    let soft_limit = model.max_instruction_fraction()
+        .map(|fraction| (cost.instruction_budget as f64 * fraction.clamp(0., 1.)) as u64);
+    let mut finish_reason = FinishReason::Length;
+    loop {
+        if let Some(at) = stop.iter().filter_map(|s| text[echo_len..].find(s.as_str())).min() {
+            text.truncate(echo_len + at);
+            finish_reason = FinishReason::Stop;
+            break;
+        }
+        if let Some(max_bytes) = max_bytes.filter(|&max| text.len() - echo_len >= max) {
+            text.truncate(floor_char_boundary(&text, echo_len + max_bytes));
+            finish_reason = FinishReason::MaxBytes;
+            break;
+        }
+        if model.is_cancelled() {
+            finish_reason = FinishReason::Cancelled;
+            break;
+        }
+        if model.is_context_full() {
+            finish_reason = FinishReason::ContextFull;
+            break;
+        }
+        if model.is_stopped() {
+            finish_reason = FinishReason::Eos;
+            break;
+        }
+        if model.generated_token_count() >= model.max_tokens() {
+            break;
+        }
+        let used = ic_cdk::api::performance_counter(0);
+        let projected = used.saturating_add(cost.decode_instructions_per_token);
+        if projected > cost.instruction_budget || soft_limit.is_some_and(|limit| used >= limit) {
+            finish_reason = FinishReason::InstructionBudget;
+            break;
+        }
+        text.push_str(&model.generate_next_token(&*tokenizer)?);
+    }
+
+    let cut_off = matches!(
+        finish_reason,
+        FinishReason::Length | FinishReason::MaxBytes | FinishReason::InstructionBudget
+    );
+    if cut_off && model.trim_trailing_partial() {
+        let len = trim_partial(&text, echo_len);
+        text.truncate(len);
+    }
+
+    let report = model.generation_report();
+    Ok(Completion {
+        text,
+        prompt_tokens,
+        completion_tokens: model.generated_token_count(),
+        finish_reason,
+        generated_tokens: if model.include_tokens() { report.generated_tokens } else { vec![] },
+        elapsed_ns: report.elapsed_ns,
+        instructions: report.instructions,
+        tokens_per_second: report.tokens_per_second,
+        seed: model.seed(),
+    })
+}
+
+/// Largest char boundary in `text` at or below `index`.
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    (0..=index.min(text.len())).rev().find(|&i| text.is_char_boundary(i)).unwrap_or(0)
+}
+
+/// Length of `text` without a trailing partial word, never cutting into `start`.
+///
+/// Heuristic: generation stopped mid-stream, so a final run of word characters
+/// (see `is_word_char`) is assumed unfinished and dropped back to the preceding
+/// whitespace or punctuation, then trailing whitespace is trimmed. Text with no
+/// break after `start` is left whole rather than emptied.
+fn trim_partial(text: &str, start: usize) -> usize {
+    let generated = &text[start..];
+    let word_start = generated.trim_end_matches(is_word_char).len();
+    if word_start == 0 {
+        return text.len();
+    }
+    start + generated[..word_start].trim_end().len()
+}
+
+/// Alphanumerics of space-delimited scripts. CJK, kana, Thai, Lao, Khmer and
+/// Myanmar don't separate words with spaces, so their characters are treated
+/// as complete units; otherwise a cut-off clause would be dropped whole.
+fn is_word_char(c: char) -> bool {
+    let unspaced = matches!(
+        c as u32,
+        0x0E00..=0x0EFF   // Thai, Lao
+            | 0x1000..=0x109F // Myanmar
+            | 0x1780..=0x17FF // Khmer
+            | 0x3040..=0x30FF // Hiragana, Katakana
+            | 0x3400..=0x4DBF // CJK Extension A
+            | 0x4E00..=0x9FFF // CJK Unified Ideographs
+            | 0xF900..=0xFAFF // CJK Compatibility Ideographs
+            | 0xFF66..=0xFF9F // Halfwidth Katakana
+            | 0x20000..=0x3FFFF // CJK Extensions B onwards
+    );
+    c.is_alphanumeric() && !unspaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trimmed(text: &str, start: usize) -> &str {
+        &text[..trim_partial(text, start)]
+    }
+
+    #[test]
+    fn drops_a_trailing_partial_word() {
+        assert_eq!(trimmed("Hello wor", 0), "Hello");
+        assert_eq!(trimmed("Hello, wor", 0), "Hello,");
+        assert_eq!(trimmed("Hello wor  ", 0), "Hello wor");
+        assert_eq!(trimmed("Hello world.", 0), "Hello world.");
+    }
+
+    #[test]
+    fn never_empties_the_output_or_cuts_the_echo() {
+        assert_eq!(trimmed("Hello", 0), "Hello");
+        assert_eq!(trimmed("Prompt: abc", 8), "Prompt: abc");
+        assert_eq!(trimmed("Prompt: ab cd", 8), "Prompt: ab");
+    }
+
+    #[test]
+    fn unspaced_scripts_are_kept() {
+        assert_eq!(trimmed("你好世界", 0), "你好世界");
+        assert_eq!(trimmed("Hi 東京", 0), "Hi 東京");
+        assert_eq!(trimmed("東京 caf", 0), "東京");
+        assert_eq!(trimmed("Ünïcödé wörd", 0), "Ünïcödé");
+    }
+
+    #[test]
+    fn floor_char_boundary_steps_back_into_a_character() {
+        let text = "aé";
+        assert_eq!(floor_char_boundary(text, 2), 1);
+        assert_eq!(floor_char_boundary(text, 3), 3);
+        assert_eq!(floor_char_boundary(text, 10), 3);
+    }
+}
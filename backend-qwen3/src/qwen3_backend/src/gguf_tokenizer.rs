@@ -0,0 +1,202 @@
+//! Rebuilds a BPE tokenizer from the `tokenizer.ggml.*` metadata embedded in GGUF files
+
+use std::collections::HashMap;
+
+use candle_core::quantized::gguf_file::{Content, Value};
+use tokenizers::models::bpe::BPE;
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::pre_tokenizers::sequence::Sequence;
+use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
+use tokenizers::{AddedToken, SplitDelimiterBehavior, Tokenizer};
+
+/// Pre-tokenizer split used by Qwen2/Qwen3's `tokenizer.json`.
+const QWEN_SPLIT_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// GGUF token type of control tokens (`<|im_end|>`, ...): matched whole and
+/// skipped when decoding with `skip_special_tokens`.
+const CONTROL_TOKEN_TYPE: i32 = 3;
+
+/// GGUF token type of user-defined tokens (`<think>`, `<tool_call>`, ...):
+/// matched whole but kept in decoded text, like their non-special entries in
+/// `tokenizer.json`.
+const USER_DEFINED_TOKEN_TYPE: i32 = 4;
+
+fn strings<'a>(content: &'a Content, key: &str) -> Result<Vec<&'a String>, String> {
+    let Some(Value::Array(items)) = content.metadata.get(key) else {
+        return Err(format!("GGUF has no '{}' array", key));
+    };
+    items.iter()
+        .map(|v| v.to_string().map_err(|e| format!("'{}': {}", key, e)))
+        .collect()
+}
+
+/// Whether the GGUF carries a byte-level BPE vocab this module can rebuild.
+pub fn has_embedded_tokenizer(content: &Content) -> bool {
+    content.metadata.get("tokenizer.ggml.model")
+        .and_then(|v| v.to_string().ok())
+        .is_some_and(|model| model == "gpt2")
+        && content.metadata.contains_key("tokenizer.ggml.tokens")
+        && content.metadata.contains_key("tokenizer.ggml.merges")
+}
+
+pub fn from_gguf(content: &Content) -> Result<Tokenizer, String> {
+    if !has_embedded_tokenizer(content) {
+        return Err("GGUF has no embedded byte-level BPE tokenizer; upload tokenizer.json".to_string());
+    }
+
+    let tokens = strings(content, "tokenizer.ggml.tokens")?;
+    let vocab: HashMap<String, u32> = tokens.iter()
+        .enumerate()
+        .map(|(id, token)| ((*token).clone(), id as u32))
+        .collect();
+    let merges = strings(content, "tokenizer.ggml.merges")?
+        .into_iter()
+        .map(|merge| merge.split_once(' ')
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .ok_or_else(|| format!("Malformed merge '{}'", merge)))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .build()
+        .map_err(|e| format!("Failed to build BPE model: {}", e))?;
+
+    let split = Split::new(SplitPattern::Regex(QWEN_SPLIT_PATTERN.to_string()), SplitDelimiterBehavior::Isolated, false)
+        .map_err(|e| format!("Invalid split pattern: {}", e))?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_pre_tokenizer(Some(Sequence::new(vec![
+        split.into(),
+        ByteLevel::new(false, false, false).into(),
+    ])));
+    tokenizer.with_decoder(Some(ByteLevel::default()));
+
+    // Control and user-defined tokens must not be split by BPE
+    if let Some(Value::Array(types)) = content.metadata.get("tokenizer.ggml.token_type") {
+        let added = |token_type: i32, special: bool| -> Vec<AddedToken> {
+            types.iter()
+                .zip(&tokens)
+                .filter(|(t, _)| t.to_i32().is_ok_and(|t| t == token_type))
+                .map(|(_, token)| AddedToken::from((*token).clone(), special).normalized(false))
+                .collect()
+        };
+        tokenizer.add_special_tokens(&added(CONTROL_TOKEN_TYPE, true));
+        tokenizer.add_tokens(&added(USER_DEFINED_TOKEN_TYPE, false));
+    }
+
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use candle_core::quantized::gguf_file::VersionedMagic;
+    use serde_json::json;
+
+    use super::*;
+
+    const MERGES: &[&str] = &["h e", "he l", "hel l", "hell o"];
+    const CONTROL: &str = "<|im_end|>";
+    const USER_DEFINED: &str = "<think>";
+
+    /// Byte-level alphabet plus the merged pieces, then the two added tokens.
+    fn vocab() -> Vec<String> {
+        let mut alphabet: Vec<char> = ByteLevel::alphabet().into_iter().collect();
+        alphabet.sort_unstable();
+        let mut vocab: Vec<String> = alphabet.into_iter().map(String::from).collect();
+        vocab.extend(["he", "hel", "hell", "hello", CONTROL, USER_DEFINED].map(String::from));
+        vocab
+    }
+
+    fn gguf(vocab: &[String]) -> Content {
+        let array = |items: Vec<String>| Value::Array(items.into_iter().map(Value::String).collect());
+        let types = vocab.iter()
+            .map(|token| match token.as_str() {
+                CONTROL => Value::I32(CONTROL_TOKEN_TYPE),
+                USER_DEFINED => Value::I32(USER_DEFINED_TOKEN_TYPE),
+                _ => Value::I32(1),
+            })
+            .collect();
+        let metadata = HashMap::from([
+            ("tokenizer.ggml.model".to_string(), Value::String("gpt2".to_string())),
+            ("tokenizer.ggml.tokens".to_string(), array(vocab.to_vec())),
+            ("tokenizer.ggml.merges".to_string(), array(MERGES.iter().map(|m| m.to_string()).collect())),
+            ("tokenizer.ggml.token_type".to_string(), Value::Array(types)),
+        ]);
+        Content { magic: VersionedMagic::GgufV3, metadata, tensor_infos: HashMap::new(), tensor_data_offset: 0 }
+    }
+
+    /// The `tokenizer.json` Qwen ships for the same vocab.
+    fn tokenizer_json(vocab: &[String]) -> Tokenizer {
+        let ids: serde_json::Map<String, serde_json::Value> = vocab.iter()
+            .enumerate()
+            .map(|(id, token)| (token.clone(), json!(id)))
+            .collect();
+        let added = |content: &str, special: bool| json!({
+            "id": vocab.iter().position(|t| t == content).unwrap(),
+            "content": content,
+            "single_word": false,
+            "lstrip": false,
+            "rstrip": false,
+            "normalized": false,
+            "special": special,
+        });
+        let config = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [added(CONTROL, true), added(USER_DEFINED, false)],
+            "normalizer": null,
+            "pre_tokenizer": {
+                "type": "Sequence",
+                "pretokenizers": [
+                    {"type": "Split", "pattern": {"Regex": QWEN_SPLIT_PATTERN}, "behavior": "Isolated", "invert": false},
+                    {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": false, "use_regex": false},
+                ],
+            },
+            "post_processor": null,
+            "decoder": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true},
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": null,
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "ignore_merges": false,
+                "vocab": ids,
+                "merges": MERGES,
+            },
+        });
+        Tokenizer::from_str(&config.to_string()).unwrap()
+    }
+
+    #[test]
+    fn embedded_tokenizer_matches_tokenizer_json() {
+        let vocab = vocab();
+        let embedded = from_gguf(&gguf(&vocab)).unwrap();
+        let reference = tokenizer_json(&vocab);
+
+        for text in ["hello", "hello world", "<think>hello</think><|im_end|>", "héllo\n<|im_end|>"] {
+            let ids = embedded.encode(text, false).unwrap().get_ids().to_vec();
+            assert_eq!(ids, reference.encode(text, false).unwrap().get_ids(), "{:?}", text);
+            for skip_special in [false, true] {
+                assert_eq!(
+                    embedded.decode(&ids, skip_special).unwrap(),
+                    reference.decode(&ids, skip_special).unwrap(),
+                    "{:?}", text,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn user_defined_tokens_are_kept_when_decoding() {
+        let tokenizer = from_gguf(&gguf(&vocab())).unwrap();
+        let ids = tokenizer.encode("<think>hello<|im_end|>", false).unwrap().get_ids().to_vec();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(tokenizer.decode(&ids, true).unwrap(), "<think>hello");
+    }
+}
@@ -0,0 +1,145 @@
+//! OpenAI-compatible `/v1/completions` over the HTTP gateway
+
+use candid::CandidType;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::generation::{self, FinishReason};
+use crate::sampling::{self, GenerateOptions};
+use crate::settings;
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct CompletionRequest {
+    prompt: String,
+    max_tokens: Option<usize>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    stop: Option<Stop>,
+    seed: Option<u64>,
+}
+
+const COMPLETIONS_PATH: &str = "/v1/completions";
+
+fn is_completions(req: &HttpRequest) -> bool {
+    let path = req.url.split('?').next().unwrap_or_default();
+    req.method.eq_ignore_ascii_case("POST") && path == COMPLETIONS_PATH
+}
+
+fn json_response(status_code: u16, body: serde_json::Value) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: body.to_string().into_bytes(),
+        upgrade: None,
+    }
+}
+
+fn error_response(status_code: u16, message: &str) -> HttpResponse {
+    json_response(status_code, json!({ "error": { "message": message } }))
+}
+
+/// Queries can't run inference, so completions are upgraded to an update call.
+#[ic_cdk::query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    if !is_completions(&req) {
+        return error_response(404, "Not found");
+    }
+    HttpResponse {
+        status_code: 200,
+        headers: vec![],
+        body: vec![],
+        upgrade: Some(true),
+    }
+}
+
+#[ic_cdk::update]
+fn http_request_update(req: HttpRequest) -> HttpResponse {
+    if !is_completions(&req) {
+        return error_response(404, "Not found");
+    }
+
+    let request: CompletionRequest = match serde_json::from_slice(&req.body) {
+        Ok(request) => request,
+        Err(e) => return error_response(400, &format!("Invalid request body: {}", e)),
+    };
+
+    let mut config = settings::generation_defaults();
+    if let Some(max_tokens) = request.max_tokens {
+        config.max_tokens = max_tokens;
+    }
+    if let Some(temperature) = request.temperature {
+        config.temperature = temperature;
+    }
+    if let Some(top_p) = request.top_p {
+        config.top_p = top_p;
+    }
+    let mut sampling = sampling::options();
+    if let Some(seed) = request.seed {
+        config.seed = seed;
+        sampling.random_seed = false;
+    }
+    let stop = match request.stop {
+        Some(Stop::One(s)) => vec![s],
+        Some(Stop::Many(v)) => v,
+        None => vec![],
+    };
+
+    let options = GenerateOptions { config, sampling };
+    let completion = match generation::complete(request.prompt, &options, &stop) {
+        Ok(completion) => completion,
+        Err(e) => return error_response(500, &e),
+    };
+
+    let finish_reason = match completion.finish_reason {
+        // OpenAI only knows these two: anything that cut the text short is "length"
+        FinishReason::Length
+        | FinishReason::ContextFull
+        | FinishReason::MaxBytes
+        | FinishReason::InstructionBudget => "length",
+        FinishReason::Eos | FinishReason::Stop | FinishReason::Cancelled => "stop",
+    };
+    let created = ic_cdk::api::time() / 1_000_000_000;
+
+    json_response(200, json!({
+        "id": format!("cmpl-{}", ic_cdk::api::time()),
+        "object": "text_completion",
+        "created": created,
+        "model": "qwen3",
+        // Not in OpenAI's response schema; pass it back as `seed` to reproduce
+        "seed": completion.seed,
+        "choices": [{
+            "text": completion.text,
+            "index": 0,
+            "logprobs": null,
+            "finish_reason": finish_reason,
+        }],
+        "usage": {
+            "prompt_tokens": completion.prompt_tokens,
+            "completion_tokens": completion.completion_tokens,
+            "total_tokens": completion.prompt_tokens + completion.completion_tokens,
+        },
+    }))
+}
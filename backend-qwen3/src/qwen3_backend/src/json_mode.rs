@@ -0,0 +1,327 @@
+//! Coarse JSON-constrained decoding: a character-level prefix checker used to
+//! mask tokens that would make the output invalid JSON
+
+use candle_core::Tensor;
+
+/// Valid tokens kept per step; sampling then happens among these.
+const JSON_CANDIDATES: usize = 40;
+
+/// Highest-ranked tokens examined per step before giving up on finding more.
+const JSON_SCAN_LIMIT: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Container {
+    Object,
+    Array,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum State {
+    /// Start of the document, after `:` or after `,` in an array
+    Value,
+    /// Just after `[`: a value or `]`
+    ValueOrEnd,
+    /// Just after `{`: a key or `}`
+    KeyOrEnd,
+    /// After `,` in an object
+    Key,
+    /// After an object key
+    Colon,
+    /// A complete value; only `,`, a closer or whitespace may follow
+    AfterValue,
+    Str { key: bool, escape: bool, unicode: u8 },
+    Number(Number),
+    /// Remaining characters of `true` / `false` / `null`
+    Literal(&'static str),
+}
+
+/// Position within a number, following the JSON grammar
+/// `-? (0 | [1-9][0-9]*) (. [0-9]+)? ([eE] [+-]? [0-9]+)?`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Number {
+    /// After `-`: a digit must follow
+    Sign,
+    /// A leading `0`, which no further integer digit may follow
+    Zero,
+    Integer,
+    /// After `.`: a digit must follow
+    Point,
+    Fraction,
+    /// After `e`/`E`: a sign or digit must follow
+    Exponent,
+    /// After the exponent's sign: a digit must follow
+    ExponentSign,
+    ExponentDigits,
+}
+
+impl Number {
+    /// Whether the number may end here.
+    fn is_complete(self) -> bool {
+        matches!(self, Number::Zero | Number::Integer | Number::Fraction | Number::ExponentDigits)
+    }
+
+    /// The position after `c`, or `None` if `c` can't continue the number.
+    fn next(self, c: char) -> Option<Number> {
+        match (self, c) {
+            (Number::Sign, '0') => Some(Number::Zero),
+            (Number::Sign, '1'..='9') => Some(Number::Integer),
+            (Number::Integer, '0'..='9') => Some(Number::Integer),
+            (Number::Zero | Number::Integer, '.') => Some(Number::Point),
+            (Number::Point | Number::Fraction, '0'..='9') => Some(Number::Fraction),
+            (Number::Zero | Number::Integer | Number::Fraction, 'e' | 'E') => Some(Number::Exponent),
+            (Number::Exponent, '+' | '-') => Some(Number::ExponentSign),
+            (Number::Exponent | Number::ExponentSign | Number::ExponentDigits, '0'..='9') => {
+                Some(Number::ExponentDigits)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Tracks whether the text so far is a prefix of some valid JSON value.
+#[derive(Clone, Debug)]
+pub struct JsonState {
+    stack: Vec<Container>,
+    state: State,
+}
+
+impl Default for JsonState {
+    fn default() -> Self {
+        Self { stack: Vec::new(), state: State::Value }
+    }
+}
+
+impl JsonState {
+    /// Whether ending the output here leaves a complete JSON value.
+    pub fn is_complete(&self) -> bool {
+        self.stack.is_empty()
+            && match self.state {
+                State::AfterValue => true,
+                State::Number(number) => number.is_complete(),
+                _ => false,
+            }
+    }
+
+    /// Whether appending `text` keeps the output a valid prefix.
+    pub fn accepts(&self, text: &str) -> bool {
+        self.clone().push_str(text)
+    }
+
+    /// Advances over `text`; on `false` the state is unspecified.
+    pub fn push_str(&mut self, text: &str) -> bool {
+        text.chars().all(|c| self.push(c))
+    }
+
+    fn value_done(&mut self) {
+        self.state = State::AfterValue;
+    }
+
+    fn push(&mut self, c: char) -> bool {
+        let ws = matches!(c, ' ' | '\t' | '\n' | '\r');
+        match self.state.clone() {
+            State::Value | State::ValueOrEnd => match c {
+                _ if ws => {}
+                '{' => {
+                    self.stack.push(Container::Object);
+                    self.state = State::KeyOrEnd;
+                }
+                '[' => {
+                    self.stack.push(Container::Array);
+                    self.state = State::ValueOrEnd;
+                }
+                '"' => self.state = State::Str { key: false, escape: false, unicode: 0 },
+                '-' => self.state = State::Number(Number::Sign),
+                '0' => self.state = State::Number(Number::Zero),
+                '1'..='9' => self.state = State::Number(Number::Integer),
+                't' => self.state = State::Literal("rue"),
+                'f' => self.state = State::Literal("alse"),
+                'n' => self.state = State::Literal("ull"),
+                ']' if self.state == State::ValueOrEnd => {
+                    self.stack.pop();
+                    self.value_done();
+                }
+                _ => return false,
+            },
+            State::KeyOrEnd | State::Key => match c {
+                _ if ws => {}
+                '"' => self.state = State::Str { key: true, escape: false, unicode: 0 },
+                '}' if self.state == State::KeyOrEnd => {
+                    self.stack.pop();
+                    self.value_done();
+                }
+                _ => return false,
+            },
+            State::Colon => match c {
+                _ if ws => {}
+                ':' => self.state = State::Value,
+                _ => return false,
+            },
+            State::AfterValue => match (c, self.stack.last()) {
+                _ if ws => {}
+                (',', Some(Container::Object)) => self.state = State::Key,
+                (',', Some(Container::Array)) => self.state = State::Value,
+                ('}', Some(Container::Object)) | (']', Some(Container::Array)) => {
+                    self.stack.pop();
+                    self.value_done();
+                }
+                _ => return false,
+            },
+            State::Str { key, escape, unicode } => {
+                if unicode > 0 {
+                    if !c.is_ascii_hexdigit() {
+                        return false;
+                    }
+                    self.state = State::Str { key, escape: false, unicode: unicode - 1 };
+                } else if escape {
+                    match c {
+                        'u' => self.state = State::Str { key, escape: false, unicode: 4 },
+                        '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't' => {
+                            self.state = State::Str { key, escape: false, unicode: 0 };
+                        }
+                        _ => return false,
+                    }
+                } else {
+                    match c {
+                        '\\' => self.state = State::Str { key, escape: true, unicode: 0 },
+                        '"' if key => self.state = State::Colon,
+                        '"' => self.value_done(),
+                        c if (c as u32) < 0x20 => return false,
+                        _ => {}
+                    }
+                }
+            }
+            State::Number(number) => match number.next(c) {
+                Some(next) => self.state = State::Number(next),
+                None if number.is_complete() => {
+                    self.value_done();
+                    return self.push(c);
+                }
+                None => return false,
+            },
+            State::Literal(rest) => {
+                if !rest.starts_with(c) {
+                    return false;
+                }
+                let rest = &rest[c.len_utf8()..];
+                if rest.is_empty() {
+                    self.value_done();
+                } else {
+                    self.state = State::Literal(rest);
+                }
+            }
+        }
+        true
+    }
+
+    /// Keeps the `JSON_CANDIDATES` highest-scoring tokens that extend a valid
+    /// prefix (EOS only once the value is complete) and masks the rest.
+    /// Errors if none of the scanned tokens fit, since sampling would then
+    /// break the grammar.
+    pub fn mask(
+        &self,
+        logits: Tensor,
+        eos_tokens: &[u32],
+        piece: impl Fn(u32) -> Option<String>,
+    ) -> candle_core::Result<Tensor> {
+        let mut values = logits.to_vec1::<f32>()?;
+        let mut ranked: Vec<usize> = (0..values.len()).collect();
+        ranked.sort_unstable_by(|&a, &b| values[b].total_cmp(&values[a]));
+
+        let mut keep = vec![false; values.len()];
+        let mut found = 0;
+        for &id in ranked.iter().take(JSON_SCAN_LIMIT) {
+            if found >= JSON_CANDIDATES || !values[id].is_finite() {
+                break;
+            }
+            let ok = if eos_tokens.contains(&(id as u32)) {
+                self.is_complete()
+            } else {
+                piece(id as u32).is_some_and(|text| !text.is_empty() && self.accepts(&text))
+            };
+            if ok {
+                keep[id] = true;
+                found += 1;
+            }
+        }
+
+        if found == 0 {
+            return Err(candle_core::Error::Msg(
+                "JSON mode: no token continues the output as valid JSON".to_string(),
+            ));
+        }
+        for (value, keep) in values.iter_mut().zip(keep) {
+            if !keep {
+                *value = f32::NEG_INFINITY;
+            }
+        }
+        Tensor::new(values, logits.device())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(text: &str) -> bool {
+        let mut state = JsonState::default();
+        state.push_str(text) && state.is_complete()
+    }
+
+    fn valid_prefix(text: &str) -> bool {
+        JsonState::default().accepts(text)
+    }
+
+    #[test]
+    fn numbers_follow_the_grammar() {
+        for text in ["0", "-0", "7", "-12", "0.5", "-3.25", "1e5", "1E+5", "2.5e-3", "0e0"] {
+            assert!(complete(text), "{} should be complete", text);
+        }
+        for text in ["-", "1.", "-0.", "1e", "1e+", "2.5E-"] {
+            assert!(valid_prefix(text), "{} should be a prefix", text);
+            assert!(!complete(text), "{} should be incomplete", text);
+        }
+        for text in ["01", "--1", "1..2", "1.e5", "1e5.0", "+1", "1e+-2", ".5", "-a"] {
+            assert!(!valid_prefix(text), "{} should be rejected", text);
+        }
+    }
+
+    #[test]
+    fn a_number_ends_at_a_delimiter_only_when_complete() {
+        assert!(complete("[1, -2.5e3]"));
+        assert!(complete(r#"{"a": 0}"#));
+        assert!(!valid_prefix("[1.]"));
+        assert!(!valid_prefix(r#"{"a": -}"#));
+        assert!(!valid_prefix("[1e,2]"));
+    }
+
+    #[test]
+    fn containers_strings_and_literals() {
+        assert!(complete(r#"{"a": [true, false, null], "b": {"c": "d\"\u00e9"}}"#));
+        assert!(complete("[]"));
+        assert!(complete(" {} "));
+        assert!(valid_prefix(r#"{"a": [tr"#));
+        assert!(!complete(r#"{"a": 1"#));
+        assert!(!valid_prefix(r#"{"a" 1}"#));
+        assert!(!valid_prefix("[1,]"));
+        assert!(!valid_prefix("]"));
+        assert!(!valid_prefix(r#"["\x"]"#));
+        assert!(!valid_prefix("[1] 2"));
+    }
+
+    #[test]
+    fn mask_keeps_only_valid_continuations() {
+        let pieces = ["{", "}", "x", "\"\"", "<eos>"];
+        let logits = Tensor::new(&[1.0f32, 2.0, 3.0, 0.5, 4.0], &candle_core::Device::Cpu).unwrap();
+        let piece = |id: u32| (pieces[id as usize] != "<eos>").then(|| pieces[id as usize].to_string());
+
+        let masked = JsonState::default().mask(logits.clone(), &[4], piece).unwrap().to_vec1::<f32>().unwrap();
+        assert!(masked[0].is_finite() && masked[3].is_finite());
+        assert!(masked[1].is_infinite() && masked[2].is_infinite() && masked[4].is_infinite());
+
+        let mut done = JsonState::default();
+        assert!(done.push_str("{}"));
+        let masked = done.mask(logits, &[4], piece).unwrap().to_vec1::<f32>().unwrap();
+        assert!(masked[4].is_finite());
+        assert!(masked[..4].iter().all(|v| v.is_infinite()));
+    }
+}
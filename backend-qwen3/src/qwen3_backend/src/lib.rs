@@ -1,21 +1,50 @@
 use std::cell::RefCell;
+use candid::CandidType;
+use serde::Deserialize;
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     DefaultMemoryImpl, StableBTreeMap,
 };
 use ic_dev_kit_rs::model_server::ModelServer;
+use ic_dev_kit_rs::text_generation::{GenerationConfig, InferenceRequest};
 
+pub mod config;
+mod generation;
+mod gguf_tokenizer;
+mod http;
+mod json_mode;
+mod lora;
+mod metrics;
+mod models;
+mod policy;
+mod queue;
 mod qwen3;
-use qwen3::Qwen3Model;
+pub mod sampling;
+mod settings;
+mod storage;
+
+// Needs the embedded fixtures; without `bench-fixtures` these benches don't exist
+#[cfg(all(feature = "canbench-rs", feature = "bench-fixtures"))]
+#[path = "../benches/inference_bench.rs"]
+mod inference_bench;
+
+use sampling::{GenerateOptions, SamplingOptions};
+use qwen3::{GenerationReport, KvStatus, ModelDetails, ModelLimits, SelfTestReport, SetupError, TokenizerInfo, LoadOptions, ModelFormat, Qwen3Model};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+const WEIGHTS_KEY: &str = "model_weights";
+const TOKENIZER_KEY: &str = "tokenizer";
+const MODEL_CONFIG_KEY: &str = "model_config";
+
+const REGISTRIES_MEMORY_ID: MemoryId = MemoryId::new(1);
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
 
     static REGISTRIES: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(REGISTRIES_MEMORY_ID)))
     );
 
     static MODEL_SERVER: ModelServer<Qwen3Model> = ModelServer::new();
@@ -34,11 +63,368 @@ ic_dev_kit_rs::generate_model_endpoints!(
     get_tokenizer: |model| model.get_tokenizer()
 );
 
-// Generate ALL upload endpoints with storage integration
-ic_dev_kit_rs::generate_upload_endpoints!(
-    guard = "ic_dev_kit_rs::auth::is_authorized",
-    registry = REGISTRIES
-);
+// Upload and stable-storage endpoints live in `storage` so they can
+// validate chunks before buffering them
+
+/// `generate` through this crate's decode loop: honours `max_output_bytes`,
+/// stop reasons and `trim_trailing_partial`, and reports usage, timing and
+/// the seed. A `None` config or `sampling` uses the stored defaults
+/// (`set_sampling_defaults`, `set_sampling_options`).
+#[ic_cdk::update]
+fn generate_ext(
+    request: InferenceRequest,
+    sampling: Option<SamplingOptions>,
+) -> Result<generation::Completion, String> {
+    let options = GenerateOptions::resolve(request.config, sampling);
+    generation::complete(request.prompt, &options, &[])
+}
+
+// ═══════════════════════════════════════════════════════════════
+//  Model Configuration
+// ═══════════════════════════════════════════════════════════════
+
+/// Runs `f` against the loaded model, erroring if none is loaded.
+fn with_model<R>(f: impl FnOnce(&mut Qwen3Model) -> Result<R, String>) -> Result<R, String> {
+    MODEL_SERVER.with(|server| server.with_model_mut(f))
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_eos_tokens(tokens: Vec<String>) -> Result<(), String> {
+    with_model(|model| model.set_eos_tokens(&tokens))
+}
+
+/// Primes the loaded model so the first real generation has representative cost.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn warmup() -> Result<(), String> {
+    with_model(|model| model.warmup())
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ReadyStatus {
+    model_loaded: bool,
+    weights_present_in_stable: bool,
+    tokenizer_present: bool,
+    last_error: Option<String>,
+}
+
+/// One-call health probe: loaded, set up in stable storage, and the last failure if any.
+#[ic_cdk::query]
+fn is_ready() -> ReadyStatus {
+    ReadyStatus {
+        model_loaded: MODEL_SERVER.with(|server| server.is_loaded()),
+        weights_present_in_stable: storage::has_stable_blob(WEIGHTS_KEY),
+        tokenizer_present: storage::has_stable_blob(TOKENIZER_KEY),
+        last_error: metrics::last_error(),
+    }
+}
+
+/// Whether new work would wait: requests are queued, or a chunked prefill is
+/// paused for `continue_prefill`. A single generation finishes within its
+/// message, so one can never be seen running.
+#[ic_cdk::query]
+fn is_busy() -> bool {
+    queue::depth() > 0 || with_model(|model| Ok(model.prefill_pending())).unwrap_or(false)
+}
+
+/// Token IDs (and other details) of the latest generation, for continuation or alignment.
+#[ic_cdk::query]
+fn last_generation() -> Result<GenerationReport, String> {
+    with_model(|model| Ok(model.generation_report()))
+}
+
+/// `(tokens_generated, max_tokens)` for an unfinished generation; the session ID
+/// comes from `last_generation`.
+#[ic_cdk::query]
+fn generation_progress(session_id: String) -> Option<(usize, usize)> {
+    with_model(|model| Ok(model.generation_progress(&session_id))).ok().flatten()
+}
+
+/// `get_model_info` plus the active repeat penalty, seed and model shape.
+#[ic_cdk::query]
+fn get_model_details() -> ModelDetails {
+    with_model(|model| Ok(model.details())).unwrap_or_else(|_| ModelDetails::unloaded())
+}
+
+/// How full the KV cache is relative to the context length.
+#[ic_cdk::query]
+fn kv_cache_status() -> Result<KvStatus, String> {
+    with_model(|model| Ok(model.kv_cache_status()))
+}
+
+/// Capabilities of the loaded model, for clients configuring themselves.
+#[ic_cdk::query]
+fn model_limits() -> Result<ModelLimits, String> {
+    with_model(|model| Ok(model.limits()))
+}
+
+/// Cancels the caller's generation `session_id`; the next decode step ends it.
+#[ic_cdk::update]
+fn request_cancel(session_id: String) -> Result<(), String> {
+    with_model(|model| model.cancel(Some(&session_id)))
+}
+
+/// Resumes a prompt whose chunked prefill paused on the instruction budget;
+/// once the prefill completes, the generation runs to completion.
+#[ic_cdk::update]
+fn continue_prefill() -> Result<generation::PrefillStatus, String> {
+    generation::resume_prefill()
+}
+
+/// Cancels whatever generation is in progress, regardless of who started it.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn cancel_current() -> Result<(), String> {
+    with_model(|model| model.cancel(None))
+}
+
+#[ic_cdk::query]
+fn token_to_piece(ids: Vec<u32>) -> Result<Vec<String>, String> {
+    with_model(|model| model.token_to_piece(&ids))
+}
+
+/// What the model would sample next, without advancing the generation.
+#[ic_cdk::query]
+fn next_token_candidates(n: usize) -> Result<Vec<(String, f32)>, String> {
+    with_model(|model| model.next_token_candidates(n))
+}
+
+/// Catches tokenizer/model mismatches after an upload, before users see garbled output.
+#[ic_cdk::query]
+fn tokenizer_self_test() -> Result<SelfTestReport, String> {
+    with_model(|model| model.tokenizer_self_test())
+}
+
+/// `None` if the piece isn't in the vocab or no model is loaded.
+#[ic_cdk::query]
+fn piece_to_token(piece: String) -> Option<u32> {
+    with_model(|model| Ok(model.piece_to_token(&piece))).ok().flatten()
+}
+
+/// Header metadata of the loaded GGUF, captured at load time.
+#[ic_cdk::query]
+fn gguf_metadata() -> Result<Vec<(String, String)>, String> {
+    with_model(|model| model.gguf_metadata())
+}
+
+/// Generates for several prompts in one call; see `generation::complete_batch`.
+#[ic_cdk::update]
+fn generate_batch(
+    requests: Vec<InferenceRequest>,
+    sampling: Option<SamplingOptions>,
+) -> Vec<Result<generation::Completion, String>> {
+    generation::complete_batch(requests, sampling)
+}
+
+/// Several samples of one prompt under distinct seeds; see `generation::complete_n`.
+#[ic_cdk::update]
+fn generate_n(
+    request: InferenceRequest,
+    n: u32,
+    seeds: Option<Vec<u64>>,
+    sampling: Option<SamplingOptions>,
+) -> Vec<Result<generation::Completion, String>> {
+    generation::complete_n(request, n, seeds, sampling)
+}
+
+/// Deterministic re-run for audits; see `generation::replay`.
+#[ic_cdk::update]
+fn replay(request: InferenceRequest, sampling: Option<SamplingOptions>) -> Result<generation::Replay, String> {
+    generation::replay(request, sampling)
+}
+
+/// Re-samples the previous prompt (e.g. with a new seed) without resending it.
+#[ic_cdk::update]
+fn regenerate(
+    config: Option<GenerationConfig>,
+    sampling: Option<SamplingOptions>,
+) -> Result<generation::Completion, String> {
+    generation::regenerate(&GenerateOptions::resolve(config, sampling))
+}
+
+/// Runs a shared prompt prefix (e.g. a system prompt) once and caches its KV
+/// state; only one prefix is kept at a time.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn cache_prefix(tokens: Vec<u32>) -> Result<String, String> {
+    with_model(|model| model.cache_prefix(&tokens))
+}
+
+/// Generates from a cached prefix, processing only `new_tokens`.
+#[ic_cdk::update]
+fn generate_with_prefix(
+    prefix_id: String,
+    new_tokens: Vec<u32>,
+    config: GenerationConfig,
+    sampling: Option<SamplingOptions>,
+) -> Result<generation::Completion, String> {
+    let options = GenerateOptions::resolve(Some(config), sampling);
+    generation::complete_with_prefix(&prefix_id, &new_tokens, &options, &[])
+}
+
+/// Reads a stable entry (or its segments), naming the key when it is absent.
+fn read_stable(key: &str, what: &str) -> Result<Vec<u8>, String> {
+    storage::read_stable_blob(key)?
+        .ok_or_else(|| format!("{} not found in stable storage under '{}'", what, key))
+}
+
+/// Parses the tokenizer stored under `key` without loading any weights.
+#[ic_cdk::query]
+fn validate_tokenizer(key: String) -> Result<TokenizerInfo, String> {
+    qwen3::inspect_tokenizer(read_stable(&key, "Tokenizer")?)
+}
+
+/// Builds a model from the given stable keys without touching the live one.
+/// With no `format` the weights are sniffed; safetensors also need `model_config`.
+fn load_model(
+    weights_key: &str,
+    tokenizer_key: &str,
+    format: Option<ModelFormat>,
+    options: &LoadOptions,
+) -> Result<Qwen3Model, SetupError> {
+    let read = |key: &str| storage::read_stable_blob(key).map_err(|e| SetupError::Unavailable(e.to_string()));
+    let weights = read(weights_key)?.ok_or_else(|| SetupError::WeightsMissing(weights_key.to_string()))?;
+    // GGUF weights can fall back to their embedded tokenizer
+    let tokenizer = read(tokenizer_key)?;
+
+    // Explicit format, then the one recorded at save time, then sniffing
+    let format = format
+        .or_else(|| storage::key_metadata(weights_key).and_then(|m| m.format))
+        .or_else(|| ModelFormat::detect(&weights))
+        .ok_or_else(|| SetupError::UnrecognizedFormat(weights_key.to_string()))?;
+
+    match format {
+        ModelFormat::Gguf => Qwen3Model::load_gguf(weights, tokenizer),
+        ModelFormat::Safetensors => {
+            let tokenizer = tokenizer.ok_or_else(|| SetupError::TokenizerMissing(tokenizer_key.to_string()))?;
+            let model_config = read(MODEL_CONFIG_KEY)?
+                .ok_or_else(|| SetupError::ModelConfigMissing(MODEL_CONFIG_KEY.to_string()))?;
+            Qwen3Model::load_safetensors(weights, tokenizer, &model_config, options)
+        }
+    }
+}
+
+/// Like `setup_model`, but loads from caller-chosen keys. Without a tokenizer key
+/// the one registered for the weights is used, then the default.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn setup_model_from(
+    weights_key: Option<String>,
+    tokenizer_key: Option<String>,
+    format: Option<ModelFormat>,
+    options: Option<LoadOptions>,
+) -> Result<(), SetupError> {
+    let weights_key = weights_key.unwrap_or_else(|| WEIGHTS_KEY.to_string());
+    let tokenizer_key = tokenizer_key
+        .or_else(|| models::tokenizer_for(&weights_key))
+        .unwrap_or_else(|| TOKENIZER_KEY.to_string());
+
+    models::check_evictable().map_err(SetupError::Unavailable)?;
+    let options = options.unwrap_or_default();
+    let model = load_model(&weights_key, &tokenizer_key, format, &options)?;
+    MODEL_SERVER.with(|server| server.set_model(model));
+    models::set_loaded(Some((&weights_key, &tokenizer_key)));
+    models::set_load_options(&options);
+    ic_dev_kit_rs::telemetry::log_info(&format!("Model loaded from '{}' / '{}'", weights_key, tokenizer_key));
+    Ok(())
+}
+
+/// Rolls back to the weights under `backup_key`: they must load before anything
+/// changes, then they are promoted onto `model_weights` and go live.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn restore_model(backup_key: String) -> Result<(), String> {
+    models::check_evictable()?;
+    let tokenizer_key = models::tokenizer_for(&backup_key).unwrap_or_else(|| TOKENIZER_KEY.to_string());
+    let model = load_model(&backup_key, &tokenizer_key, None, &models::load_options())
+        .map_err(|e| format!("Backup '{}' failed to load, live model untouched: {}", backup_key, e))?;
+    storage::promote(&backup_key, WEIGHTS_KEY)
+        .map_err(|e| format!("Backup loaded but promoting it to '{}' failed: {}", WEIGHTS_KEY, e))?;
+
+    MODEL_SERVER.with(|server| server.set_model(model));
+    models::set_loaded(Some((WEIGHTS_KEY, &tokenizer_key)));
+    ic_dev_kit_rs::telemetry::log_info(&format!("Model restored from '{}'", backup_key));
+    Ok(())
+}
+
+/// Tokens `test_load` generates from the probe prompt.
+const TEST_LOAD_MAX_TOKENS: usize = 16;
+
+/// Loads staged weights into a throwaway model, runs a short greedy probe
+/// generation and drops it; the live model keeps serving throughout. Both
+/// models are on the heap meanwhile, so this needs room for two.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn test_load(
+    weights_key: String,
+    tokenizer_key: String,
+    probe_prompt: String,
+) -> Result<generation::Completion, String> {
+    let mut model = load_model(&weights_key, &tokenizer_key, None, &LoadOptions::default())
+        .map_err(|e| format!("'{}' failed to load: {}", weights_key, e))?;
+    // Built-in sampling options, so operator settings can't skew the probe
+    let options = GenerateOptions {
+        config: GenerationConfig {
+            temperature: 0.,
+            max_tokens: TEST_LOAD_MAX_TOKENS,
+            ..GenerationConfig::default()
+        },
+        sampling: SamplingOptions::default(),
+    };
+    generation::complete_on(&mut model, probe_prompt, &options, &[])
+        .map_err(|e| format!("'{}' loaded but the probe generation failed: {}", weights_key, e))
+}
+
+/// Rebuilds the live model from its safetensors base with the LoRA adapter
+/// under `adapter_key` merged in; `alpha` defaults to the adapter's rank.
+/// The base weights in stable storage are left as they are.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn apply_lora(adapter_key: String, alpha: Option<f32>) -> Result<(), String> {
+    let (weights_key, tokenizer_key) = models::loaded().ok_or("Model not loaded")?;
+    models::check_evictable()?;
+    let weights = read_stable(&weights_key, "Weights")?;
+    if ModelFormat::detect(&weights) != Some(ModelFormat::Safetensors) {
+        return Err("LoRA needs safetensors base weights; quantized GGUF tensors can't be merged".to_string());
+    }
+    let adapter = read_stable(&adapter_key, "Adapter")?;
+    let tokenizer = read_stable(&tokenizer_key, "Tokenizer")?;
+    let model_config = read_stable(MODEL_CONFIG_KEY, "Model config")?;
+
+    let model = Qwen3Model::load_safetensors_with_lora(
+        weights, tokenizer, &model_config, &models::load_options(), &adapter, alpha,
+    )?;
+    MODEL_SERVER.with(|server| server.set_model(model));
+    models::set_loaded(Some((&weights_key, &tokenizer_key)));
+    lora::set_active(Some(adapter_key.clone()));
+    ic_dev_kit_rs::telemetry::log_info(&format!("LoRA '{}' applied to '{}'", adapter_key, weights_key));
+    Ok(())
+}
+
+/// Reloads the live model's base weights, dropping any merged adapter.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn clear_lora() -> Result<(), String> {
+    let adapter_key = lora::active().ok_or("No LoRA adapter applied")?;
+    let (weights_key, tokenizer_key) = models::loaded().ok_or("Model not loaded")?;
+    models::check_evictable()?;
+    let model = load_model(&weights_key, &tokenizer_key, None, &models::load_options())?;
+    MODEL_SERVER.with(|server| server.set_model(model));
+    models::set_loaded(Some((&weights_key, &tokenizer_key)));
+    ic_dev_kit_rs::telemetry::log_info(&format!("LoRA '{}' cleared", adapter_key));
+    Ok(())
+}
+
+/// Stable key of the adapter merged into the live model, if any.
+#[ic_cdk::query]
+fn active_lora() -> Option<String> {
+    lora::active()
+}
+
+/// Drops the loaded weights, tokenizer and KV cache to reclaim heap.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn unload_model() -> Result<(), String> {
+    if !MODEL_SERVER.with(|server| server.is_loaded()) {
+        return Err("Model not loaded".to_string());
+    }
+    models::check_evictable()?;
+    MODEL_SERVER.with(|server| server.unload());
+    models::set_loaded(None);
+    ic_dev_kit_rs::telemetry::log_info("Model unloaded");
+    Ok(())
+}
 
 // ═══════════════════════════════════════════════════════════════
 //  Lifecycle Hooks
@@ -55,15 +441,46 @@ async fn init() {
 fn pre_upgrade() {
     let auth_bytes = ic_dev_kit_rs::auth::save_to_bytes();
     REGISTRIES.with(|r| ic_dev_kit_rs::storage::save_bytes(r, "__auth__", auth_bytes));
-    ic_dev_kit_rs::telemetry::log_info("Pre-upgrade: saved auth state");
+    metrics::save();
+    ic_dev_kit_rs::telemetry::log_info("Pre-upgrade: saved auth state and metrics");
 }
 
 #[ic_cdk::post_upgrade]
 fn post_upgrade() {
     let auth_bytes = REGISTRIES.with(|r| ic_dev_kit_rs::storage::load_bytes(r, "__auth__"));
     ic_dev_kit_rs::auth::init_from_saved(auth_bytes);
+    settings::restore();
+    sampling::restore();
+    policy::restore();
+    queue::restore();
+    metrics::restore();
+    models::restore();
+    storage::restore();
     ic_dev_kit_rs::telemetry::init();
     ic_dev_kit_rs::telemetry::log_info("Post-upgrade: restored auth state");
+
+    if settings::auto_reload() {
+        auto_reload_model();
+    }
+}
+
+/// Loads the default weights (and tokenizer, if uploaded) from stable storage.
+fn auto_reload_model() {
+    if !storage::has_stable_blob(WEIGHTS_KEY) {
+        ic_dev_kit_rs::telemetry::log_info("Post-upgrade: auto-reload skipped, no weights stored");
+        return;
+    }
+
+    let start = ic_cdk::api::performance_counter(0);
+    match load_model(WEIGHTS_KEY, TOKENIZER_KEY, None, &models::load_options()) {
+        Ok(model) => {
+            MODEL_SERVER.with(|server| server.set_model(model));
+            models::set_loaded(Some((WEIGHTS_KEY, TOKENIZER_KEY)));
+            let used = ic_cdk::api::performance_counter(0) - start;
+            ic_dev_kit_rs::telemetry::log_info(&format!("Post-upgrade: model reloaded ({} instructions)", used));
+        }
+        Err(e) => ic_dev_kit_rs::telemetry::log_info(&format!("Post-upgrade: auto-reload failed: {}", e)),
+    }
 }
 
 ic_cdk::export_candid!();
\ No newline at end of file
@@ -0,0 +1,70 @@
+//! Merge-at-load LoRA adapters for full-precision (safetensors) weights
+//!
+//! Quantized GGUF tensors can't be updated in place, so adapters only apply
+//! to safetensors checkpoints: each targeted weight becomes
+//! `W + (alpha / r) · B·A` before the model is built.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Tensor};
+
+/// PEFT saves adapter tensors under the wrapped model's path.
+const PEFT_PREFIX: &str = "base_model.model.";
+
+thread_local! {
+    /// Stable key of the adapter merged into the live model, if any
+    static ACTIVE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn active() -> Option<String> {
+    ACTIVE.with(|a| a.borrow().clone())
+}
+
+pub fn set_active(adapter_key: Option<String>) {
+    ACTIVE.with(|a| *a.borrow_mut() = adapter_key);
+}
+
+/// Loads the base safetensors and merges every `lora_A` / `lora_B` pair of
+/// the PEFT-format `adapter` into the weight it targets. `alpha` defaults to
+/// the rank, i.e. a scale of 1.
+pub fn merge(
+    weights: &[u8],
+    adapter: &[u8],
+    alpha: Option<f32>,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>, String> {
+    let mut tensors = candle_core::safetensors::load_buffer(weights, device)
+        .map_err(|e| format!("Failed to read safetensors: {}", e))?;
+    let adapter = candle_core::safetensors::load_buffer(adapter, device)
+        .map_err(|e| format!("Failed to read adapter: {}", e))?;
+
+    let mut merged = 0;
+    for (name, a) in &adapter {
+        let Some(path) = name.strip_suffix(".lora_A.weight") else { continue };
+        let b = adapter.get(&format!("{}.lora_B.weight", path))
+            .ok_or_else(|| format!("Adapter has {} but no matching lora_B", name))?;
+        let target = format!("{}.weight", path.strip_prefix(PEFT_PREFIX).unwrap_or(path));
+        let base = tensors.get(&target)
+            .ok_or_else(|| format!("Adapter targets '{}', which the base weights lack", target))?;
+
+        let rank = a.dim(0).map_err(|e| e.to_string())?;
+        let scale = alpha.unwrap_or(rank as f32) as f64 / rank as f64;
+        let updated = merge_one(base, a, b, scale)
+            .map_err(|e| format!("Failed to merge '{}': {}", target, e))?;
+        tensors.insert(target, updated);
+        merged += 1;
+    }
+
+    if merged == 0 {
+        return Err("Adapter contains no lora_A / lora_B pairs".to_string());
+    }
+    ic_dev_kit_rs::telemetry::log_info(&format!("Merged LoRA into {} tensors", merged));
+    Ok(tensors)
+}
+
+/// `base + scale · B·A`, computed in f32 and cast back to the base dtype.
+fn merge_one(base: &Tensor, a: &Tensor, b: &Tensor, scale: f64) -> candle_core::Result<Tensor> {
+    let delta = b.to_dtype(DType::F32)?.matmul(&a.to_dtype(DType::F32)?)?;
+    (base.to_dtype(DType::F32)? + (delta * scale)?)?.to_dtype(base.dtype())
+}
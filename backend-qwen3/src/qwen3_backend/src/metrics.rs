@@ -0,0 +1,104 @@
+//! Lifetime inference counters, persisted across upgrades
+
+use std::cell::RefCell;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::storage::{load_state, save_state};
+
+const METRICS_KEY: &str = "__inference_metrics__";
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct InferenceMetrics {
+    pub total_generations: u64,
+    pub total_tokens: u64,
+    pub total_instructions: u64,
+    pub error_count: u64,
+}
+
+thread_local! {
+    static METRICS: RefCell<InferenceMetrics> = RefCell::new(InferenceMetrics::default());
+
+    /// Error from the most recent failed generation and its IC time, cleared
+    /// when a new one starts cleanly
+    static LAST_ERROR: RefCell<Option<(String, u64)>> = const { RefCell::new(None) };
+}
+
+pub fn save() {
+    METRICS.with(|m| save_state(METRICS_KEY, &*m.borrow()));
+}
+
+pub fn restore() {
+    let metrics = load_state(METRICS_KEY).unwrap_or_default();
+    METRICS.with(|m| *m.borrow_mut() = metrics);
+}
+
+pub fn last_error() -> Option<String> {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map(|(error, _)| error.clone()))
+}
+
+fn set_last_error(error: Option<&str>) {
+    let entry = error.map(|e| (e.to_string(), ic_cdk::api::time()));
+    LAST_ERROR.with(|last| *last.borrow_mut() = entry);
+}
+
+/// Called once per generation, after the prompt has been processed.
+pub fn record_generation(result: &Result<String, String>) {
+    let ok = result.is_ok();
+    set_last_error(result.as_ref().err().map(String::as_str));
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        m.total_generations += 1;
+        if ok {
+            m.total_tokens += 1;
+        } else {
+            m.error_count += 1;
+        }
+    });
+}
+
+/// Called when a generation is refused before it reaches the model (busy, no
+/// model loaded), so those failures count too.
+pub fn record_refusal(error: &str) {
+    set_last_error(Some(error));
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        m.total_generations += 1;
+        m.error_count += 1;
+    });
+}
+
+/// Called once per decode step.
+pub fn record_token(result: &Result<String, String>) {
+    let ok = result.is_ok();
+    if let Err(e) = result {
+        set_last_error(Some(e.as_str()));
+    }
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        if ok {
+            m.total_tokens += 1;
+        } else {
+            m.error_count += 1;
+        }
+    });
+}
+
+/// Called with the instructions of each forward pass, measured around the pass
+/// itself so nothing is counted twice or missed between hooks.
+pub fn record_instructions(instructions: u64) {
+    METRICS.with(|m| m.borrow_mut().total_instructions += instructions);
+}
+
+/// Most recent generation failure and when it happened (ns since the epoch),
+/// for clients that lost the response.
+#[ic_cdk::query]
+fn last_generation_error() -> Option<(String, u64)> {
+    LAST_ERROR.with(|e| e.borrow().clone())
+}
+
+#[ic_cdk::query]
+fn inference_metrics() -> InferenceMetrics {
+    METRICS.with(|m| m.borrow().clone())
+}
@@ -0,0 +1,166 @@
+//! Registry of weights/tokenizer pairings, so several models can share one canister
+//!
+//! Only one model is resident at a time (`MODEL_SERVER` has a single slot), so
+//! loading another evicts it; a pinned model refuses eviction until unpinned.
+
+use std::cell::{Cell, RefCell};
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::qwen3::LoadOptions;
+use crate::storage::{self, load_state, save_state, KeyMetadata};
+
+const MODEL_REGISTRY_KEY: &str = "__model_registry__";
+const LOAD_OPTIONS_KEY: &str = "__load_options__";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Pairing {
+    weights_key: String,
+    tokenizer_key: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ModelEntry {
+    pub weights_key: String,
+    pub tokenizer_key: String,
+    /// Sidecar recorded when the weights were saved
+    pub metadata: Option<KeyMetadata>,
+    pub loaded: bool,
+    /// Resident and protected from eviction by `pin_model`
+    pub pinned: bool,
+}
+
+thread_local! {
+    static PAIRINGS: RefCell<Vec<Pairing>> = const { RefCell::new(Vec::new()) };
+
+    /// Pairing of the live model; not persisted since the model itself isn't
+    static LOADED: RefCell<Option<Pairing>> = const { RefCell::new(None) };
+
+    static PINNED: Cell<bool> = const { Cell::new(false) };
+
+    /// Options of the last explicit load, reused when the model is rebuilt
+    static LOAD_OPTIONS: RefCell<Option<LoadOptions>> = const { RefCell::new(None) };
+}
+
+pub fn restore() {
+    let pairings = load_state(MODEL_REGISTRY_KEY).unwrap_or_default();
+    PAIRINGS.with(|p| *p.borrow_mut() = pairings);
+    let options = load_state(LOAD_OPTIONS_KEY);
+    LOAD_OPTIONS.with(|o| *o.borrow_mut() = options);
+}
+
+/// Remembers the options a model was loaded with; persists across upgrades.
+pub fn set_load_options(options: &LoadOptions) {
+    save_state(LOAD_OPTIONS_KEY, options);
+    LOAD_OPTIONS.with(|o| *o.borrow_mut() = Some(options.clone()));
+}
+
+/// Options for rebuilding the model (LoRA, restore, auto-reload): those of
+/// the last `setup_model_from`, or the defaults.
+pub fn load_options() -> LoadOptions {
+    LOAD_OPTIONS.with(|o| o.borrow().clone()).unwrap_or_default()
+}
+
+/// Records (or re-points) the tokenizer used with `weights_key`.
+pub fn register(weights_key: &str, tokenizer_key: &str) {
+    PAIRINGS.with(|p| {
+        let mut p = p.borrow_mut();
+        p.retain(|pairing| pairing.weights_key != weights_key);
+        p.push(Pairing { weights_key: weights_key.to_string(), tokenizer_key: tokenizer_key.to_string() });
+        save_state(MODEL_REGISTRY_KEY, &*p);
+    });
+}
+
+/// The tokenizer registered for `weights_key`, if any.
+pub fn tokenizer_for(weights_key: &str) -> Option<String> {
+    PAIRINGS.with(|p| {
+        p.borrow().iter()
+            .find(|pairing| pairing.weights_key == weights_key)
+            .map(|pairing| pairing.tokenizer_key.clone())
+    })
+}
+
+/// Marks the pairing as live (registering it) or, with `None`, that nothing is
+/// loaded. Either way the live model is the plain base, without any adapter.
+pub fn set_loaded(keys: Option<(&str, &str)>) {
+    crate::lora::set_active(None);
+    if let Some((weights_key, tokenizer_key)) = keys {
+        register(weights_key, tokenizer_key);
+    }
+    let loaded = keys.map(|(weights_key, tokenizer_key)| Pairing {
+        weights_key: weights_key.to_string(),
+        tokenizer_key: tokenizer_key.to_string(),
+    });
+    if loaded.is_none() {
+        PINNED.with(|p| p.set(false));
+    }
+    LOADED.with(|l| *l.borrow_mut() = loaded);
+}
+
+/// Errors if the resident model is pinned, before anything replaces or unloads it.
+pub fn check_evictable() -> Result<(), String> {
+    if PINNED.with(|p| p.get()) {
+        let (weights_key, _) = loaded().unwrap_or_default();
+        return Err(format!("Model '{}' is pinned; call pin_model(false) first", weights_key));
+    }
+    Ok(())
+}
+
+fn entry(pairing: &Pairing, loaded: bool) -> ModelEntry {
+    ModelEntry {
+        weights_key: pairing.weights_key.clone(),
+        tokenizer_key: pairing.tokenizer_key.clone(),
+        metadata: storage::key_metadata(&pairing.weights_key),
+        loaded,
+        pinned: loaded && PINNED.with(|p| p.get()),
+    }
+}
+
+/// `(weights_key, tokenizer_key)` of the live model.
+pub fn loaded() -> Option<(String, String)> {
+    LOADED.with(|l| l.borrow().clone().map(|p| (p.weights_key, p.tokenizer_key)))
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn register_model(weights_key: String, tokenizer_key: String) {
+    register(&weights_key, &tokenizer_key);
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn unregister_model(weights_key: String) -> bool {
+    PAIRINGS.with(|p| {
+        let mut p = p.borrow_mut();
+        let before = p.len();
+        p.retain(|pairing| pairing.weights_key != weights_key);
+        save_state(MODEL_REGISTRY_KEY, &*p);
+        p.len() != before
+    })
+}
+
+#[ic_cdk::query]
+fn list_models() -> Vec<ModelEntry> {
+    let loaded = LOADED.with(|l| l.borrow().clone());
+    PAIRINGS.with(|p| {
+        p.borrow().iter()
+            .map(|pairing| entry(pairing, loaded.as_ref() == Some(pairing)))
+            .collect()
+    })
+}
+
+/// Keeps the resident model from being replaced, re-merged or unloaded while
+/// pinned; every load path and `clear_all_stable` refuse until it is unpinned.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn pin_model(pinned: bool) -> Result<(), String> {
+    if pinned && LOADED.with(|l| l.borrow().is_none()) {
+        return Err("Model not loaded".to_string());
+    }
+    PINNED.with(|p| p.set(pinned));
+    Ok(())
+}
+
+/// Models currently in memory; at most one with the single-slot model server.
+#[ic_cdk::query]
+fn resident_models() -> Vec<ModelEntry> {
+    LOADED.with(|l| l.borrow().iter().map(|pairing| entry(pairing, true)).collect())
+}
@@ -0,0 +1,267 @@
+//! Admission checks run before any generation starts
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+use crate::storage::{load_state, save_state};
+
+const RATE_LIMIT_KEY: &str = "__rate_limit__";
+const ACCESS_MODE_KEY: &str = "__inference_access__";
+const ALLOWLIST_KEY: &str = "__inference_allowlist__";
+const MIN_CYCLES_KEY: &str = "__min_cycles_for_generation__";
+
+/// Balance below which generations are refused, enough for a few
+/// max-length generations on top of the freezing threshold; 0 disables it.
+const DEFAULT_MIN_CYCLES_FOR_GENERATION: u128 = 100_000_000_000;
+
+/// Who may call `generate`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum AccessMode {
+    #[default]
+    Public,
+    /// Principals added via `add_inference_principal`, plus authorized ones
+    AllowlistOnly,
+    /// Only principals passing `ic_dev_kit_rs::auth::is_authorized`
+    OwnerOnly,
+}
+
+/// Requests per minute allowed per caller; 0 disables the limit.
+const DEFAULT_REQUESTS_PER_MINUTE: u32 = 30;
+
+/// Any bucket refills completely within a minute, after which it is no
+/// different from a missing one.
+const FULL_REFILL_NS: u64 = 60_000_000_000;
+
+struct Bucket {
+    tokens: f64,
+    updated_ns: u64,
+}
+
+thread_local! {
+    static REQUESTS_PER_MINUTE: Cell<u32> = const { Cell::new(DEFAULT_REQUESTS_PER_MINUTE) };
+    static BUCKETS: RefCell<HashMap<Principal, Bucket>> = RefCell::new(HashMap::new());
+    static ACCESS_MODE: Cell<AccessMode> = const { Cell::new(AccessMode::Public) };
+    static ALLOWLIST: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    static MIN_CYCLES: Cell<u128> = const { Cell::new(DEFAULT_MIN_CYCLES_FOR_GENERATION) };
+
+    /// Set while running work whose caller was admitted in an earlier message
+    static ADMITTED: Cell<bool> = const { Cell::new(false) };
+    /// Who a queued request runs for, while the drain message runs it
+    static ACTING_CALLER: Cell<Option<Principal>> = const { Cell::new(None) };
+}
+
+pub fn restore() {
+    let rpm = load_state(RATE_LIMIT_KEY).unwrap_or(DEFAULT_REQUESTS_PER_MINUTE);
+    REQUESTS_PER_MINUTE.with(|r| r.set(rpm));
+
+    let mode = load_state(ACCESS_MODE_KEY).unwrap_or_default();
+    ACCESS_MODE.with(|m| m.set(mode));
+    let allowlist: Vec<Principal> = load_state(ALLOWLIST_KEY).unwrap_or_default();
+    ALLOWLIST.with(|a| *a.borrow_mut() = allowlist.into_iter().collect());
+    let min_cycles = load_state(MIN_CYCLES_KEY).unwrap_or(DEFAULT_MIN_CYCLES_FOR_GENERATION);
+    MIN_CYCLES.with(|m| m.set(min_cycles));
+}
+
+/// Rejects the current call if it may not start a generation.
+pub fn check_generation_allowed() -> Result<(), String> {
+    check_cycles()?;
+    if ADMITTED.with(|a| a.get()) {
+        return Ok(());
+    }
+    let caller = ic_cdk::api::msg_caller();
+    check_access(&caller)?;
+    check_rate_limit(caller)
+}
+
+/// Runs `f` skipping the access and rate-limit checks (cycles are still
+/// checked), for work whose caller already passed them: queued requests
+/// when enqueued, and each item of a multi-generation call once per call.
+pub fn run_admitted<R>(f: impl FnOnce() -> R) -> R {
+    let previous = ADMITTED.with(|a| a.replace(true));
+    let result = f();
+    ADMITTED.with(|a| a.set(previous));
+    result
+}
+
+/// Runs `f` admitted and on behalf of `caller`, for queued requests, which
+/// run in a message the canister sends itself.
+pub fn run_as<R>(caller: Principal, f: impl FnOnce() -> R) -> R {
+    let previous = ACTING_CALLER.with(|c| c.replace(Some(caller)));
+    let result = run_admitted(f);
+    ACTING_CALLER.with(|c| c.set(previous));
+    result
+}
+
+/// Who the current generation is for: the enqueuing caller inside `run_as`,
+/// otherwise `msg_caller`.
+pub fn caller() -> Principal {
+    ACTING_CALLER.with(|c| c.get()).unwrap_or_else(ic_cdk::api::msg_caller)
+}
+
+/// Refuses up front rather than risking a trap partway through a generation.
+fn check_cycles() -> Result<(), String> {
+    let min = MIN_CYCLES.with(|m| m.get());
+    let balance = ic_cdk::api::canister_cycle_balance();
+    if balance < min {
+        return Err(format!(
+            "Insufficient cycles: balance {} is below the {} required to generate",
+            balance, min
+        ));
+    }
+    Ok(())
+}
+
+fn check_access(caller: &Principal) -> Result<(), String> {
+    match ACCESS_MODE.with(|m| m.get()) {
+        AccessMode::Public => Ok(()),
+        AccessMode::OwnerOnly => ic_dev_kit_rs::auth::is_authorized()
+            .map_err(|_| "Inference is restricted to the canister owners".to_string()),
+        AccessMode::AllowlistOnly => {
+            if ALLOWLIST.with(|a| a.borrow().contains(caller)) || ic_dev_kit_rs::auth::is_authorized().is_ok() {
+                Ok(())
+            } else {
+                Err(format!("Principal {} is not allowed to run inference", caller))
+            }
+        }
+    }
+}
+
+/// Anonymous callers share one bucket and get a quarter of the normal allowance.
+fn allowance(caller: &Principal) -> f64 {
+    let rpm = REQUESTS_PER_MINUTE.with(|r| r.get());
+    if *caller == Principal::anonymous() {
+        (rpm / 4).max(1) as f64
+    } else {
+        rpm as f64
+    }
+}
+
+/// Token bucket: refills continuously at `allowance` per minute, bursts up to
+/// `allowance`. Buckets idle long enough to be full are dropped, so the map
+/// only holds callers seen within the last minute.
+fn check_rate_limit(caller: Principal) -> Result<(), String> {
+    take_request(caller, ic_cdk::api::time())
+}
+
+/// `check_rate_limit` at IC time `now`.
+fn take_request(caller: Principal, now: u64) -> Result<(), String> {
+    if REQUESTS_PER_MINUTE.with(|r| r.get()) == 0 {
+        return Ok(());
+    }
+
+    let capacity = allowance(&caller);
+    BUCKETS.with(|b| {
+        let mut buckets = b.borrow_mut();
+        buckets.retain(|_, bucket| now.saturating_sub(bucket.updated_ns) < FULL_REFILL_NS);
+        let bucket = buckets.entry(caller).or_insert(Bucket { tokens: capacity, updated_ns: now });
+
+        let elapsed_min = now.saturating_sub(bucket.updated_ns) as f64 / 60e9;
+        bucket.tokens = (bucket.tokens + elapsed_min * capacity).min(capacity);
+        bucket.updated_ns = now;
+
+        if bucket.tokens < 1. {
+            return Err(format!("Rate limit exceeded: {} requests per minute", capacity));
+        }
+        bucket.tokens -= 1.;
+        Ok(())
+    })
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_rate_limit(requests_per_minute: u32) {
+    save_state(RATE_LIMIT_KEY, &requests_per_minute);
+    REQUESTS_PER_MINUTE.with(|r| r.set(requests_per_minute));
+    BUCKETS.with(|b| b.borrow_mut().clear());
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_min_cycles_for_generation(cycles: u128) {
+    save_state(MIN_CYCLES_KEY, &cycles);
+    MIN_CYCLES.with(|m| m.set(cycles));
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_inference_access(mode: AccessMode) {
+    save_state(ACCESS_MODE_KEY, &mode);
+    ACCESS_MODE.with(|m| m.set(mode));
+}
+
+#[ic_cdk::query]
+fn get_inference_access() -> AccessMode {
+    ACCESS_MODE.with(|m| m.get())
+}
+
+fn save_allowlist() {
+    let allowlist: Vec<Principal> = ALLOWLIST.with(|a| a.borrow().iter().copied().collect());
+    save_state(ALLOWLIST_KEY, &allowlist);
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn add_inference_principal(principal: Principal) {
+    ALLOWLIST.with(|a| a.borrow_mut().insert(principal));
+    save_allowlist();
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn remove_inference_principal(principal: Principal) -> bool {
+    let removed = ALLOWLIST.with(|a| a.borrow_mut().remove(&principal));
+    save_allowlist();
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn bursts_up_to_the_allowance_then_refills() {
+        REQUESTS_PER_MINUTE.with(|r| r.set(4));
+        for _ in 0..4 {
+            assert!(take_request(user(1), 0).is_ok());
+        }
+        assert!(take_request(user(1), 0).is_err());
+        // A quarter of a minute refills one of four requests
+        assert!(take_request(user(1), 15 * SECOND).is_ok());
+        assert!(take_request(user(1), 15 * SECOND).is_err());
+        // Other callers have their own bucket
+        assert!(take_request(user(2), 15 * SECOND).is_ok());
+    }
+
+    #[test]
+    fn anonymous_callers_get_a_quarter() {
+        REQUESTS_PER_MINUTE.with(|r| r.set(8));
+        let anonymous = Principal::anonymous();
+        assert!(take_request(anonymous, 0).is_ok());
+        assert!(take_request(anonymous, 0).is_ok());
+        assert!(take_request(anonymous, 0).is_err());
+
+        // Never below one request per minute
+        REQUESTS_PER_MINUTE.with(|r| r.set(2));
+        assert_eq!(allowance(&anonymous), 1.);
+    }
+
+    #[test]
+    fn zero_disables_the_limit_and_idle_buckets_are_dropped() {
+        REQUESTS_PER_MINUTE.with(|r| r.set(0));
+        for _ in 0..100 {
+            assert!(take_request(user(1), 0).is_ok());
+        }
+        assert!(BUCKETS.with(|b| b.borrow().is_empty()));
+
+        REQUESTS_PER_MINUTE.with(|r| r.set(1));
+        assert!(take_request(user(1), 0).is_ok());
+        assert!(take_request(user(2), 30 * SECOND).is_ok());
+        assert!(take_request(user(2), 90 * SECOND).is_ok());
+        // user(1) was idle for over a minute, so only user(2) is left
+        assert_eq!(BUCKETS.with(|b| b.borrow().keys().copied().collect::<Vec<_>>()), vec![user(2)]);
+    }
+}
@@ -0,0 +1,181 @@
+//! FIFO queue of generation requests, drained one at a time
+//!
+//! Callers enqueue instead of retrying against a busy model, can poll their
+//! position, and collect the completion once it has run.
+//!
+//! A timer pops the head into `RUNNING` and has the canister call its own
+//! `run_queued`, so the pop is committed before the generation starts. If the
+//! generation traps, only that call rolls back; the reply callback records the
+//! failure and the queue moves on.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use candid::{CandidType, Principal};
+use ic_dev_kit_rs::text_generation::InferenceRequest;
+use serde::Deserialize;
+
+use crate::generation::{self, Completion};
+use crate::sampling::{GenerateOptions, SamplingOptions};
+use crate::storage::{load_state, save_state};
+
+const MAX_QUEUE_DEPTH_KEY: &str = "__max_queue_depth__";
+
+const DEFAULT_MAX_QUEUE_DEPTH: usize = 16;
+
+/// Finished results kept for `queued_result`; the oldest are dropped first.
+const MAX_KEPT_RESULTS: usize = 64;
+
+struct Queued {
+    id: u64,
+    caller: Principal,
+    request: InferenceRequest,
+    sampling: Option<SamplingOptions>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum QueuedResult {
+    /// Still waiting; 1 means it runs next, 0 that it is running
+    Pending { position: usize },
+    Done(Result<Completion, String>),
+}
+
+thread_local! {
+    static QUEUE: RefCell<VecDeque<Queued>> = const { RefCell::new(VecDeque::new()) };
+    static RESULTS: RefCell<BTreeMap<u64, (Principal, Result<Completion, String>)>> =
+        const { RefCell::new(BTreeMap::new()) };
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+    /// Popped and handed to `run_queued`, until it stores a result
+    static RUNNING: RefCell<Option<Queued>> = const { RefCell::new(None) };
+    /// A drain timer is pending or a request is running, so enqueueing needn't arm another
+    static SCHEDULED: Cell<bool> = const { Cell::new(false) };
+    static MAX_QUEUE_DEPTH: Cell<usize> = const { Cell::new(DEFAULT_MAX_QUEUE_DEPTH) };
+}
+
+/// Reloads the depth limit after an upgrade; queued requests don't survive one.
+pub fn restore() {
+    let depth = load_state(MAX_QUEUE_DEPTH_KEY).unwrap_or(DEFAULT_MAX_QUEUE_DEPTH);
+    MAX_QUEUE_DEPTH.with(|m| m.set(depth));
+}
+
+/// Requests waiting or running.
+pub fn depth() -> usize {
+    QUEUE.with(|q| q.borrow().len()) + RUNNING.with(|r| r.borrow().is_some() as usize)
+}
+
+fn schedule() {
+    if !SCHEDULED.with(|s| s.replace(true)) {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_next);
+    }
+}
+
+/// Hands the request at the head of the queue to `run_queued`, in its own
+/// message so each one gets a full instruction budget, then re-arms while
+/// work remains.
+fn run_next() {
+    let Some(queued) = QUEUE.with(|q| q.borrow_mut().pop_front()) else {
+        SCHEDULED.with(|s| s.set(false));
+        return;
+    };
+    RUNNING.with(|r| *r.borrow_mut() = Some(queued));
+
+    ic_cdk::futures::spawn(async {
+        let result = ic_cdk::call::Call::unbounded_wait(ic_cdk::api::canister_self(), "run_queued").await;
+        // Still set only if `run_queued` trapped (or never ran) and rolled back
+        if let Some(queued) = RUNNING.with(|r| r.borrow_mut().take()) {
+            let error = match result {
+                Err(e) => format!("Queued generation failed: {}", e),
+                Ok(_) => "Queued generation did not run".to_string(),
+            };
+            store_result(queued.id, queued.caller, Err(error));
+        }
+        SCHEDULED.with(|s| s.set(false));
+        if QUEUE.with(|q| !q.borrow().is_empty()) {
+            schedule();
+        }
+    });
+}
+
+fn is_self() -> Result<(), String> {
+    if ic_cdk::api::msg_caller() == ic_cdk::api::canister_self() {
+        Ok(())
+    } else {
+        Err("Only the canister itself may run queued requests".to_string())
+    }
+}
+
+/// Runs the request `run_next` popped, on behalf of whoever queued it.
+#[ic_cdk::update(guard = "is_self")]
+fn run_queued() {
+    let Some(queued) = RUNNING.with(|r| r.borrow_mut().take()) else { return };
+
+    // Defaults are read when the request runs, not when it was queued
+    let options = GenerateOptions::resolve(queued.request.config, queued.sampling);
+    // Admission was checked when the caller enqueued; this message comes from the canister
+    let result = crate::policy::run_as(queued.caller, || generation::complete(queued.request.prompt, &options, &[]));
+    store_result(queued.id, queued.caller, result);
+}
+
+fn store_result(id: u64, caller: Principal, result: Result<Completion, String>) {
+    RESULTS.with(|r| {
+        let mut r = r.borrow_mut();
+        r.insert(id, (caller, result));
+        while r.len() > MAX_KEPT_RESULTS {
+            r.pop_first();
+        }
+    });
+}
+
+/// Queues `request` behind any pending ones, returning its ID. Rejects
+/// callers the admission policy would refuse, and overflow past the depth limit.
+#[ic_cdk::update]
+fn enqueue_generation(request: InferenceRequest, sampling: Option<SamplingOptions>) -> Result<u64, String> {
+    crate::policy::check_generation_allowed()?;
+    if let Some(sampling) = &sampling {
+        sampling.validate()?;
+    }
+    let max = MAX_QUEUE_DEPTH.with(|m| m.get());
+    if depth() >= max {
+        return Err(format!("Queue full ({} pending), retry later", max));
+    }
+
+    let id = NEXT_ID.with(|n| {
+        let id = n.get();
+        n.set(id + 1);
+        id
+    });
+    let caller = ic_cdk::api::msg_caller();
+    QUEUE.with(|q| q.borrow_mut().push_back(Queued { id, caller, request, sampling }));
+    schedule();
+    Ok(id)
+}
+
+/// 1-based place in line, or `None` once it has run (or was never queued).
+#[ic_cdk::query]
+fn queue_position(request_id: u64) -> Option<usize> {
+    QUEUE.with(|q| q.borrow().iter().position(|queued| queued.id == request_id).map(|i| i + 1))
+}
+
+/// The caller's queued request: its position while pending, its result once run.
+#[ic_cdk::query]
+fn queued_result(request_id: u64) -> Option<QueuedResult> {
+    if let Some(position) = queue_position(request_id) {
+        return Some(QueuedResult::Pending { position });
+    }
+    if RUNNING.with(|r| r.borrow().as_ref().is_some_and(|queued| queued.id == request_id)) {
+        return Some(QueuedResult::Pending { position: 0 });
+    }
+    let caller = ic_cdk::api::msg_caller();
+    RESULTS.with(|r| {
+        r.borrow().get(&request_id)
+            .filter(|(owner, _)| *owner == caller)
+            .map(|(_, result)| QueuedResult::Done(result.clone()))
+    })
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_max_queue_depth(depth: usize) {
+    save_state(MAX_QUEUE_DEPTH_KEY, &depth);
+    MAX_QUEUE_DEPTH.with(|m| m.set(depth));
+}
@@ -1,78 +1,399 @@
 //! Qwen3 model - only Qwen3-specific logic
 
-use candle_transformers::generation::LogitsProcessor;
+use candid::{CandidType, Principal};
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use candle_transformers::models::qwen3::{Config as Qwen3Config, ModelForCausalLM as Qwen3Full};
 use candle_transformers::models::quantized_qwen3::ModelWeights as QuantizedQwen3;
+use serde::Deserialize;
+use std::sync::Arc;
 use ::tokenizers::Tokenizer;  // Use :: to explicitly refer to the external crate
 
+use crate::gguf_tokenizer;
+use crate::json_mode::JsonState;
+use crate::lora;
+use crate::metrics;
+use crate::sampling::{self, GenerateOptions, SamplingOptions};
+
 // Import from ic-dev-kit-rs
 use ic_dev_kit_rs::candle::*;
 use ic_dev_kit_rs::text_generation::*;
 
+/// On-disk layout of the uploaded weights.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ModelFormat {
+    Gguf,
+    Safetensors,
+}
+
+impl ModelFormat {
+    /// Guesses the format from the leading bytes.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"GGUF") {
+            return Some(Self::Gguf);
+        }
+        // safetensors: u64 little-endian header length followed by a JSON object
+        if bytes.len() > 8 && bytes[8] == b'{' {
+            return Some(Self::Safetensors);
+        }
+        None
+    }
+}
+
+/// Activation/weight dtype for full-precision checkpoints.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ComputeMode {
+    #[default]
+    ForceF32,
+    F16,
+    BF16,
+}
+
+impl ComputeMode {
+    fn dtype(self) -> DType {
+        match self {
+            Self::ForceF32 => DType::F32,
+            Self::F16 => DType::F16,
+            Self::BF16 => DType::BF16,
+        }
+    }
+}
+
+/// Knobs applied at load time. candle's quantized Qwen3 has no flash-attention
+/// or mask-caching switches, so for GGUF these are fixed and only
+/// `compute_mode` (safetensors) takes effect.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct LoadOptions {
+    pub compute_mode: ComputeMode,
+}
+
+/// Why a model couldn't be set up, so callers can tell a missing upload from
+/// corrupt weights. Key-carrying variants name the stable key.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum SetupError {
+    WeightsMissing(String),
+    TokenizerMissing(String),
+    ModelConfigMissing(String),
+    UnrecognizedFormat(String),
+    GgufParseFailed(String),
+    SafetensorsParseFailed(String),
+    TokenizerInvalid(String),
+    ModelConfigInvalid(String),
+    ModelBuildFailed(String),
+    /// Stable storage couldn't be read, or the live model can't be replaced
+    Unavailable(String),
+}
+
+impl std::fmt::Display for SetupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WeightsMissing(key) => write!(f, "Weights not found in stable storage under '{}'", key),
+            Self::TokenizerMissing(key) => write!(f, "Tokenizer not found in stable storage under '{}'", key),
+            Self::ModelConfigMissing(key) => write!(f, "Model config not found in stable storage under '{}'", key),
+            Self::UnrecognizedFormat(key) => write!(f, "Unrecognized weights format under '{}'", key),
+            Self::GgufParseFailed(msg) => write!(f, "Failed to parse GGUF: {}", msg),
+            Self::SafetensorsParseFailed(msg) => write!(f, "Failed to read safetensors: {}", msg),
+            Self::TokenizerInvalid(msg) => write!(f, "Failed to load tokenizer: {}", msg),
+            Self::ModelConfigInvalid(msg) => write!(f, "Failed to parse model config: {}", msg),
+            Self::ModelBuildFailed(msg) => write!(f, "Failed to load model: {}", msg),
+            Self::Unavailable(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<SetupError> for String {
+    fn from(e: SetupError) -> Self {
+        e.to_string()
+    }
+}
+
+/// Details of the most recent generation that `InferenceResponse` doesn't carry.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct GenerationReport {
+    /// Identifies this generation for `generation_progress`
+    pub session_id: String,
+    /// Prompt tokens processed before `generated_tokens`
+    pub prompt_len: usize,
+    /// Tokens sampled so far, excluding the prompt
+    pub generated_tokens: Vec<u32>,
+    /// IC time from the start of the generation to its latest step. Time is
+    /// fixed within a message, so this is 0 for single-call generations.
+    pub elapsed_ns: u64,
+    /// Instructions spent in the prefill and decode steps
+    pub instructions: u64,
+    /// From `elapsed_ns` when it is non-zero, otherwise estimated from
+    /// `instructions` at `ESTIMATED_INSTRUCTIONS_PER_SECOND`
+    pub tokens_per_second: f64,
+    /// The requested `repeat_last_n` when it was clamped to `max_repeat_last_n`
+    pub repeat_last_n_clamped_from: Option<usize>,
+    /// Seed the sampler actually used, after stored defaults and `random_seed`
+    pub seed: u64,
+}
+
+/// Rough instruction throughput of a subnet, for rates when IC time can't resolve a generation.
+const ESTIMATED_INSTRUCTIONS_PER_SECOND: f64 = 2e9;
+
+/// Extends the kit's `ModelInfo` with the active sampling setup and model shape.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ModelDetails {
+    pub loaded: bool,
+    pub current_tokens: usize,
+    /// Tokens the last prompt was processed as; generated tokens follow it
+    pub prompt_len: usize,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    pub seed: u64,
+    pub architecture: String,
+    pub context_length: usize,
+    pub vocab_size: usize,
+}
+
+impl ModelDetails {
+    /// What a fresh load would report, before any generation.
+    pub fn unloaded() -> Self {
+        let config = GenerationConfig::default();
+        Self {
+            loaded: false,
+            current_tokens: 0,
+            prompt_len: 0,
+            repeat_penalty: config.repeat_penalty,
+            repeat_last_n: config.repeat_last_n,
+            seed: config.seed,
+            architecture: String::new(),
+            context_length: 0,
+            vocab_size: 0,
+        }
+    }
+}
+
+/// What a client needs to size its requests for the loaded model.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ModelLimits {
+    /// From the GGUF header (`qwen3.context_length`) or the safetensors config
+    pub context_length: usize,
+    pub vocab_size: usize,
+    /// `max_tokens` applied to requests without a config
+    pub max_tokens_default: usize,
+    /// Instructions a single generate call may spend (`CostModel`)
+    pub instruction_budget: u64,
+}
+
+/// KV-cache occupancy of the loaded model.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KvStatus {
+    /// Positions cached in every layer (prompt plus generated tokens)
+    pub used_positions: usize,
+    pub max_positions: usize,
+    pub layers: usize,
+}
+
+/// What `validate_tokenizer` found in uploaded tokenizer bytes.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenizerInfo {
+    pub vocab_size: usize,
+    /// Which of the default EOS tokens the vocab contains
+    pub eos_candidates_found: Vec<String>,
+    /// `tokenizer.json` carries no template, so this reports whether the
+    /// ChatML markers Qwen's template relies on are in the vocab
+    pub has_chat_template: bool,
+}
+
+/// Text `tokenizer_self_test` round-trips: ASCII, punctuation, digits,
+/// whitespace runs and multi-byte characters.
+const SELF_TEST_PROBE: &str = "Hello, world! 123 +  tabs\tand\nnewlines — café 你好 🙂";
+
+/// Result of `tokenizer_self_test` on the loaded tokenizer.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SelfTestReport {
+    pub probe: String,
+    pub token_ids: Vec<u32>,
+    pub decoded: String,
+    pub roundtrip_ok: bool,
+    /// Each resolved EOS ID with its vocab piece; `None` if the ID has none
+    pub eos_pieces: Vec<(u32, Option<String>)>,
+    /// Every EOS piece is a special `<|...|>` / `</s>`-style marker
+    pub eos_ok: bool,
+    /// Human-readable description of each failed check
+    pub discrepancies: Vec<String>,
+}
+
+/// Quantized GGUF or full-precision safetensors weights. Clones share the
+/// weight tensors and snapshot the KV cache.
+#[derive(Clone)]
+enum Weights {
+    Quantized(QuantizedQwen3),
+    Full(Qwen3Full),
+}
+
+impl Weights {
+    /// Returns logits for the last position, shaped `(batch, vocab)`.
+    fn forward(&mut self, input: &Tensor, offset: usize) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Quantized(model) => model.forward(input, offset),
+            Self::Full(model) => model.forward(input, offset)?.squeeze(1),
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        match self {
+            Self::Quantized(model) => model.clear_kv_cache(),
+            Self::Full(model) => model.clear_kv_cache(),
+        }
+    }
+}
+
 pub struct Qwen3Model {
-    model: QuantizedQwen3,
-    tokenizer: Tokenizer,
+    model: Weights,
+    /// Where the weights live; inputs are created here too
+    device: Device,
+    format: ModelFormat,
+    /// Shared with every handle from `get_tokenizer`, so handing one out doesn't copy the vocab
+    tokenizer: Arc<Tokenizer>,
+    /// Cached: `get_vocab_size(true)` materializes the whole vocab map
+    vocab_size: usize,
     logits_processor: LogitsProcessor,
     tokens: Vec<u32>,
+    /// Positions already in the KV cache (prompt plus generated tokens)
+    kv_len: usize,
     repeat_penalty: f32,
     repeat_last_n: usize,
-    eos_token: u32,
+    /// Seed handed to `LogitsProcessor` for the current generation
+    seed: u64,
+    eos_tokens: Vec<u32>,
+    /// From `tokenizer.ggml.bos_token_id`, else the first of `DEFAULT_BOS_TOKENS` in the vocab
+    bos_token: Option<u32>,
+    gguf_metadata: Vec<(String, String)>,
+    context_length: usize,
+    /// Transformer blocks, each with its own KV cache; 0 if the weights don't say
+    num_layers: usize,
+    sampling: SamplingOptions,
+    /// Sample by plain argmax (greedy, or temperature 0)
+    argmax: bool,
+    /// Bumped by every `init_generation`; doubles as the session ID
+    generation_id: u64,
+    max_tokens: usize,
+    prefix: Option<CachedPrefix>,
+    /// Bumped by every `cache_prefix`, so each cached prefix gets a fresh ID
+    prefix_counter: u64,
+    /// Bytes of echoed prompt at the start of the generated text
+    echo_len: usize,
+    /// Who started the current generation; only they may cancel it
+    owner: Principal,
+    cancelled: bool,
+    /// Tokens of the last prompt as processed (after truncation), kept for `regenerate`
+    prompt_tokens: Vec<u32>,
+    /// Cached prefix the last prompt followed, if any
+    prompt_prefix: Option<String>,
+    /// `ic_cdk::api::time()` at `prepare` and after the latest step
+    started_at: u64,
+    last_step_at: u64,
+    instructions: u64,
+    /// Mirostat's running truncation threshold, in bits
+    mu: f32,
+    repeat_last_n_clamped_from: Option<usize>,
+    /// Grammar state of the output so far, when `json_mode` is on
+    json: Option<JsonState>,
+    /// Record `logprobs` for each sampled token (set by `replay`)
+    capture_logprobs: bool,
+    logprobs: Vec<f32>,
+    /// Prompt tokens left after a chunked prefill paused on the instruction budget
+    pending_prefill: Option<PendingPrefill>,
+    /// Generated tokens whose bytes don't yet form complete UTF-8, held back
+    /// so streamed text never contains a split character
+    undecoded: Vec<u32>,
 }
 
-pub struct Qwen3Tokenizer(Tokenizer);
+/// Where a paused chunked prefill resumes.
+struct PendingPrefill {
+    remaining: Vec<u32>,
+    total: usize,
+    /// Echoed prompt to prepend to the first generated token
+    echo: String,
+}
+
+/// A prompt prefix already run through the model, restorable by ID.
+struct CachedPrefix {
+    id: String,
+    model: Weights,
+    len: usize,
+}
+
+/// Used when the weights don't declare a context length.
+const DEFAULT_CONTEXT_LENGTH: usize = 8192;
+
+/// Stop tokens tried at load time, in order.
+const DEFAULT_EOS_TOKENS: &[&str] = &["<|endoftext|>", "<|im_end|>"];
+
+/// BOS spellings tried at load time, in order, when the weights don't name one.
+/// Qwen3 vocabs have none of these; Qwen prompts normally start without a BOS.
+const DEFAULT_BOS_TOKENS: &[&str] = &["<|begin_of_text|>", "<s>", "<bos>"];
+
+pub struct Qwen3Tokenizer {
+    tokenizer: Arc<Tokenizer>,
+    vocab_size: usize,
+}
 
 impl TokenizerHandle for Qwen3Tokenizer {
     fn encode(&self, text: &str) -> Result<Vec<u32>, String> {
-        self.0.encode(text, true)
+        self.tokenizer.encode(text, true)
             .map(|e| e.get_ids().to_vec())
             .map_err(|e| format!("Encode error: {}", e))
     }
 
     fn decode(&self, tokens: &[u32]) -> Result<String, String> {
-        self.0.decode(tokens, false).map_err(|e| format!("Decode error: {}", e))
+        self.tokenizer.decode(tokens, false).map_err(|e| format!("Decode error: {}", e))
     }
 
     fn vocab_size(&self) -> usize {
-        self.0.get_vocab_size(true)
+        self.vocab_size
     }
 }
 
+/// Tokenizes `prompt`, then prepends `bos` unless it is already there.
+///
+/// An empty or whitespace-only prompt is rejected before the BOS goes in, so
+/// it can't pass as a one-token prompt; a zero-length input tensor would
+/// otherwise fail deep inside candle.
+fn encode_prompt(tokenizer: &dyn TokenizerHandle, prompt: &str, bos: Option<u32>) -> Result<Vec<u32>, String> {
+    let mut tokens = if prompt.trim().is_empty() { Vec::new() } else { tokenizer.encode(prompt)? };
+    if tokens.is_empty() {
+        return Err("prompt produced no tokens".to_string());
+    }
+    if let Some(bos) = bos.filter(|&bos| tokens.first() != Some(&bos)) {
+        tokens.insert(0, bos);
+    }
+    Ok(tokens)
+}
+
 impl CandleModel for Qwen3Model {
+    /// Without separate tokenizer bytes, the tokenizer embedded in the GGUF is used.
+    /// The macro-generated `setup_model` loads `model_weights` / `tokenizer`
+    /// through here, so this honours the pin and records those keys as loaded.
     fn load(weights: Vec<u8>, config: Option<Vec<u8>>) -> Result<Self, String> {
-        let tokenizer_bytes = config.ok_or("Tokenizer required")?;
-        let tokenizer = Tokenizer::from_bytes(&tokenizer_bytes)
-            .map_err(|e| format!("Failed to load tokenizer: {}", e))?;
-
-        // Use helpers from ic-dev-kit - note: this is the text_generation::tokenizers module
-        let eos_token = tokenizers::find_eos_token(&tokenizer);
-        let (content, mut cursor) = gguf::load_content(weights)?;
-        let device = gguf::cpu_device();
-
-        let model = QuantizedQwen3::from_gguf(content, &mut cursor, &device)
-            .map_err(|e| format!("Failed to load model: {}", e))?;
-
-        Ok(Self {
-            model,
-            tokenizer,
-            tokens: vec![],
-            logits_processor: LogitsProcessor::new(299792458, None, None),
-            repeat_penalty: 1.,
-            repeat_last_n: 64,
-            eos_token,
-        })
+        crate::models::check_evictable()?;
+        let model = Self::load_gguf(weights, config)?;
+        crate::models::set_loaded(Some((crate::WEIGHTS_KEY, crate::TOKENIZER_KEY)));
+        Ok(model)
     }
 
     fn metadata(&self) -> ModelMetadata {
         ModelMetadata {
             name: "Qwen3".to_string(),
             version: "0.5B".to_string(),
-            architecture: "Qwen3 (Quantized GGUF)".to_string(),
+            architecture: match self.format {
+                ModelFormat::Gguf => "Qwen3 (Quantized GGUF)".to_string(),
+                ModelFormat::Safetensors => "Qwen3 (safetensors)".to_string(),
+            },
             parameters: 500_000_000,
-            context_length: Some(8192),
+            context_length: Some(self.context_length),
         }
     }
 
     fn reset(&mut self) {
         self.tokens.clear();
+        self.clear_kv_cache();
     }
 }
 
@@ -83,25 +404,22 @@ impl AutoregressiveModel for Qwen3Model {
         tokenizer: &dyn TokenizerHandle,
         config: &GenerationConfig,
     ) -> Result<String, String> {
-        let temp = if config.temperature <= 0. { None } else { Some(config.temperature) };
-        let top_p = if config.top_p <= 0. || config.top_p >= 1. { None } else { Some(config.top_p) };
-
-        self.logits_processor = LogitsProcessor::new(config.seed, temp, top_p);
-        self.repeat_penalty = config.repeat_penalty;
-        self.repeat_last_n = config.repeat_last_n;
-        self.tokens.clear();
-
-        let tokens = tokenizer.encode(&prompt)?;
-        self.process(&tokens).map_err(|e| e.to_string())
+        let options = GenerateOptions { config: config.clone(), sampling: sampling::options() };
+        self.init_with_options(prompt, tokenizer, &options)
     }
 
     fn generate_next_token(&mut self, _tokenizer: &dyn TokenizerHandle) -> Result<String, String> {
-        let last_token = *self.tokens.last().ok_or("No tokens generated")?;
-        self.process(&[last_token]).map_err(|e| e.to_string())
+        let result = self.next_token();
+        metrics::record_token(&result);
+        result
     }
 
+    /// Also true once another decode step would likely exceed the instruction
+    /// budget, so the kit's `generate` loop stops short of the message limit.
     fn is_generation_complete(&self) -> bool {
-        self.tokens.last().map_or(false, |&t| t == self.eos_token)
+        let cost = crate::settings::cost_model();
+        let projected = ic_cdk::api::performance_counter(0).saturating_add(cost.decode_instructions_per_token);
+        self.is_stopped() || projected > cost.instruction_budget
     }
 
     fn generated_token_count(&self) -> usize {
@@ -109,28 +427,933 @@ impl AutoregressiveModel for Qwen3Model {
     }
 }
 
+/// Flattens GGUF header metadata to strings; arrays (e.g. the vocab) are summarized by length.
+fn summarize_gguf(content: &gguf_file::Content) -> Vec<(String, String)> {
+    use std::collections::BTreeMap;
+
+    let mut pairs: Vec<(String, String)> = content.metadata.iter()
+        .map(|(key, value)| {
+            let value = match value {
+                gguf_file::Value::String(s) => s.clone(),
+                gguf_file::Value::Array(items) => format!("[{} items]", items.len()),
+                other => format!("{:?}", other),
+            };
+            (key.clone(), value)
+        })
+        .collect();
+
+    let mut dtypes: BTreeMap<String, usize> = BTreeMap::new();
+    for info in content.tensor_infos.values() {
+        *dtypes.entry(format!("{:?}", info.ggml_dtype)).or_default() += 1;
+    }
+    let dtypes = dtypes.iter()
+        .map(|(dtype, count)| format!("{}={}", dtype, count))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    pairs.push(("tensor_count".to_string(), content.tensor_infos.len().to_string()));
+    pairs.push(("tensor_dtypes".to_string(), dtypes));
+    pairs.sort();
+    pairs
+}
+
+/// Best-effort `(architecture, quantization)` from a GGUF header, reading no
+/// tensor data. Quantization is the most common tensor dtype.
+pub fn describe_gguf(bytes: &[u8]) -> (Option<String>, Option<String>) {
+    use std::collections::HashMap;
+
+    let Ok(content) = gguf_file::Content::read(&mut std::io::Cursor::new(bytes)) else {
+        return (None, None);
+    };
+    let architecture = content.metadata.get("general.architecture")
+        .and_then(|v| v.to_string().ok())
+        .cloned();
+
+    let mut dtypes: HashMap<String, usize> = HashMap::new();
+    for info in content.tensor_infos.values() {
+        *dtypes.entry(format!("{:?}", info.ggml_dtype)).or_default() += 1;
+    }
+    let quantization = dtypes.into_iter().max_by_key(|(_, count)| *count).map(|(dtype, _)| dtype);
+    (architecture, quantization)
+}
+
+/// Parses tokenizer bytes on their own, without any weights.
+pub fn inspect_tokenizer(bytes: Vec<u8>) -> Result<TokenizerInfo, String> {
+    let tokenizer = parse_tokenizer(bytes)?;
+    Ok(TokenizerInfo {
+        vocab_size: tokenizer.get_vocab_size(true),
+        eos_candidates_found: DEFAULT_EOS_TOKENS.iter()
+            .filter(|name| tokenizer.token_to_id(name).is_some())
+            .map(|name| name.to_string())
+            .collect(),
+        has_chat_template: ["<|im_start|>", "<|im_end|>"].iter()
+            .all(|name| tokenizer.token_to_id(name).is_some()),
+    })
+}
+
+/// Special-token spellings used for end of text/turn: `<|im_end|>`, `</s>`, `<eos>`.
+fn is_end_marker(piece: &str) -> bool {
+    let special = piece.starts_with('<') && piece.ends_with('>');
+    let lower = piece.to_ascii_lowercase();
+    special && ["end", "eos", "eot", "/s"].iter().any(|marker| lower.contains(marker))
+}
+
+/// The one place the compute device is chosen. On-chain this is always the
+/// CPU; `cuda` / `metal` builds (off-chain testing only) use GPU 0 if present.
+pub fn inference_device() -> Device {
+    #[cfg(feature = "cuda")]
+    if let Ok(device) = Device::new_cuda(0) {
+        return device;
+    }
+    #[cfg(feature = "metal")]
+    if let Ok(device) = Device::new_metal(0) {
+        return device;
+    }
+    gguf::cpu_device()
+}
+
+fn parse_tokenizer(bytes: Vec<u8>) -> Result<Tokenizer, SetupError> {
+    Tokenizer::from_bytes(&bytes).map_err(|e| SetupError::TokenizerInvalid(e.to_string()))
+}
+
 impl Qwen3Model {
+    /// `CandleModel::load` with the failure kept structured.
+    pub fn load_gguf(weights: Vec<u8>, tokenizer: Option<Vec<u8>>) -> Result<Self, SetupError> {
+        if ModelFormat::detect(&weights) != Some(ModelFormat::Gguf) {
+            return Err(SetupError::GgufParseFailed("weights are not a GGUF file".to_string()));
+        }
+
+        // Use helpers from ic-dev-kit
+        let (content, mut cursor) = gguf::load_content(weights).map_err(SetupError::GgufParseFailed)?;
+        let tokenizer = match tokenizer {
+            Some(bytes) => parse_tokenizer(bytes)?,
+            None => gguf_tokenizer::from_gguf(&content).map_err(SetupError::TokenizerInvalid)?,
+        };
+        let device = inference_device();
+        let metadata = summarize_gguf(&content);
+        let context_length = content.metadata.get("qwen3.context_length")
+            .and_then(|v| v.to_u32().ok())
+            .map_or(DEFAULT_CONTEXT_LENGTH, |n| n as usize);
+        let bos_token = content.metadata.get("tokenizer.ggml.bos_token_id")
+            .and_then(|v| v.to_u32().ok());
+        let num_layers = content.metadata.get("qwen3.block_count")
+            .and_then(|v| v.to_u32().ok())
+            .map_or(0, |n| n as usize);
+
+        let model = QuantizedQwen3::from_gguf(content, &mut cursor, &device)
+            .map_err(|e| SetupError::ModelBuildFailed(e.to_string()))?;
+
+        let mut model = Self::from_parts(Weights::Quantized(model), ModelFormat::Gguf, tokenizer, device);
+        model.gguf_metadata = metadata;
+        model.context_length = context_length;
+        model.num_layers = num_layers;
+        model.bos_token = bos_token.or(model.bos_token);
+        Ok(model)
+    }
+
+    /// Loads full-precision weights; `model_config` is the HF `config.json`.
+    pub fn load_safetensors(
+        weights: Vec<u8>,
+        tokenizer: Vec<u8>,
+        model_config: &[u8],
+        options: &LoadOptions,
+    ) -> Result<Self, SetupError> {
+        let tokenizer = parse_tokenizer(tokenizer)?;
+        if ModelFormat::detect(&weights) != Some(ModelFormat::Safetensors) {
+            return Err(SetupError::SafetensorsParseFailed("weights are not a safetensors file".to_string()));
+        }
+
+        let device = inference_device();
+        let vb = VarBuilder::from_buffered_safetensors(weights, options.compute_mode.dtype(), &device)
+            .map_err(|e| SetupError::SafetensorsParseFailed(e.to_string()))?;
+        Self::from_var_builder(vb, tokenizer, model_config, device)
+    }
+
+    /// Like `load_safetensors`, with the LoRA `adapter` merged into the weights
+    /// first (see `lora::merge`). The base tensors are unpacked in full, so this
+    /// needs more heap than a plain load.
+    pub fn load_safetensors_with_lora(
+        weights: Vec<u8>,
+        tokenizer: Vec<u8>,
+        model_config: &[u8],
+        options: &LoadOptions,
+        adapter: &[u8],
+        alpha: Option<f32>,
+    ) -> Result<Self, SetupError> {
+        let tokenizer = parse_tokenizer(tokenizer)?;
+        if ModelFormat::detect(&weights) != Some(ModelFormat::Safetensors) {
+            return Err(SetupError::SafetensorsParseFailed("weights are not a safetensors file".to_string()));
+        }
+
+        let device = inference_device();
+        let tensors = lora::merge(&weights, adapter, alpha, &device).map_err(SetupError::ModelBuildFailed)?;
+        drop(weights);
+        let vb = VarBuilder::from_tensors(tensors, options.compute_mode.dtype(), &device);
+        Self::from_var_builder(vb, tokenizer, model_config, device)
+    }
+
+    fn from_var_builder(
+        vb: VarBuilder,
+        tokenizer: Tokenizer,
+        model_config: &[u8],
+        device: Device,
+    ) -> Result<Self, SetupError> {
+        let config: Qwen3Config = serde_json::from_slice(model_config)
+            .map_err(|e| SetupError::ModelConfigInvalid(e.to_string()))?;
+        let model = Qwen3Full::new(&config, vb)
+            .map_err(|e| SetupError::ModelBuildFailed(e.to_string()))?;
+
+        let mut model = Self::from_parts(Weights::Full(model), ModelFormat::Safetensors, tokenizer, device);
+        model.context_length = config.max_position_embeddings;
+        model.num_layers = config.num_hidden_layers;
+        Ok(model)
+    }
+
+    fn from_parts(model: Weights, format: ModelFormat, tokenizer: Tokenizer, device: Device) -> Self {
+        // Keep whichever defaults the vocab knows; fall back to the ic-dev-kit heuristic
+        let mut eos_tokens: Vec<u32> = DEFAULT_EOS_TOKENS.iter()
+            .filter_map(|name| tokenizer.token_to_id(name))
+            .collect();
+        if eos_tokens.is_empty() {
+            // Only this fallback scans the vocab; the ids are resolved once per load
+            // Note: this is the text_generation::tokenizers module
+            eos_tokens.push(tokenizers::find_eos_token(&tokenizer));
+        }
+        let bos_token = DEFAULT_BOS_TOKENS.iter().find_map(|name| tokenizer.token_to_id(name));
+        let vocab_size = tokenizer.get_vocab_size(true);
+
+        Self {
+            model,
+            device,
+            format,
+            tokenizer: Arc::new(tokenizer),
+            vocab_size,
+            tokens: vec![],
+            kv_len: 0,
+            logits_processor: LogitsProcessor::new(299792458, None, None),
+            repeat_penalty: 1.,
+            repeat_last_n: 64,
+            seed: 299792458,
+            eos_tokens,
+            bos_token,
+            gguf_metadata: vec![],
+            context_length: DEFAULT_CONTEXT_LENGTH,
+            num_layers: 0,
+            sampling: SamplingOptions::default(),
+            argmax: false,
+            generation_id: 0,
+            max_tokens: 0,
+            prefix: None,
+            prefix_counter: 0,
+            echo_len: 0,
+            owner: Principal::anonymous(),
+            cancelled: false,
+            prompt_tokens: vec![],
+            prompt_prefix: None,
+            started_at: 0,
+            last_step_at: 0,
+            instructions: 0,
+            mu: 0.,
+            repeat_last_n_clamped_from: None,
+            json: None,
+            capture_logprobs: false,
+            logprobs: vec![],
+            pending_prefill: None,
+            undecoded: vec![],
+        }
+    }
+
+    pub fn gguf_metadata(&self) -> Result<Vec<(String, String)>, String> {
+        match self.format {
+            ModelFormat::Gguf => Ok(self.gguf_metadata.clone()),
+            ModelFormat::Safetensors => Err("Loaded model is not GGUF".to_string()),
+        }
+    }
+
+    pub fn generation_report(&self) -> GenerationReport {
+        let elapsed_ns = self.last_step_at.saturating_sub(self.started_at);
+        let seconds = if elapsed_ns > 0 {
+            elapsed_ns as f64 / 1e9
+        } else {
+            self.instructions as f64 / ESTIMATED_INSTRUCTIONS_PER_SECOND
+        };
+
+        GenerationReport {
+            session_id: self.generation_id.to_string(),
+            prompt_len: self.prompt_len(),
+            generated_tokens: self.tokens.clone(),
+            elapsed_ns,
+            instructions: self.instructions,
+            tokens_per_second: if seconds > 0. { self.tokens.len() as f64 / seconds } else { 0. },
+            repeat_last_n_clamped_from: self.repeat_last_n_clamped_from,
+            seed: self.seed,
+        }
+    }
+
+    pub fn details(&self) -> ModelDetails {
+        ModelDetails {
+            loaded: true,
+            current_tokens: self.tokens.len(),
+            prompt_len: self.prompt_len(),
+            repeat_penalty: self.repeat_penalty,
+            repeat_last_n: self.repeat_last_n,
+            seed: self.seed,
+            architecture: self.metadata().architecture,
+            context_length: self.context_length,
+            vocab_size: self.vocab_size,
+        }
+    }
+
+    /// candle keeps the per-layer caches private, so occupancy comes from
+    /// `kv_len`, which every forward pass and cache clear keeps in step.
+    pub fn kv_cache_status(&self) -> KvStatus {
+        KvStatus {
+            used_positions: self.kv_len,
+            max_positions: self.context_length,
+            layers: self.num_layers,
+        }
+    }
+
+    pub fn limits(&self) -> ModelLimits {
+        ModelLimits {
+            context_length: self.context_length,
+            vocab_size: self.vocab_size,
+            max_tokens_default: crate::settings::generation_defaults().max_tokens,
+            instruction_budget: crate::settings::cost_model().instruction_budget,
+        }
+    }
+
+    /// Stops the current generation at the next decode step. With a
+    /// `session_id`, only its owner can cancel and only while it is current.
+    pub fn cancel(&mut self, session_id: Option<&str>) -> Result<(), String> {
+        if let Some(session_id) = session_id {
+            if session_id != self.generation_id.to_string() {
+                return Err(format!("Session '{}' is not in progress", session_id));
+            }
+            if ic_cdk::api::msg_caller() != self.owner {
+                return Err("Only the caller that started a generation can cancel it".to_string());
+            }
+        }
+        self.cancelled = true;
+        Ok(())
+    }
+
+    /// While on, each step records the sampled token's log-probability under
+    /// the distribution it was drawn from (after penalties and filters, at temperature 1).
+    pub fn set_capture_logprobs(&mut self, on: bool) {
+        self.capture_logprobs = on;
+    }
+
+    pub fn logprobs(&self) -> &[f32] {
+        &self.logprobs
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Cancelled, out of context or just emitted EOS; unlike
+    /// `is_generation_complete`, ignores the instruction budget.
+    pub fn is_stopped(&self) -> bool {
+        self.cancelled
+            || self.is_context_full()
+            || self.tokens.last().map_or(false, |t| self.eos_tokens.contains(t))
+    }
+
+    /// Whether the next decode step would run past the trained context window.
+    pub fn is_context_full(&self) -> bool {
+        self.kv_len >= self.context_length
+    }
+
+    /// `max_tokens` of the current generation.
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    /// Length in bytes of the echoed prompt leading the generated text.
+    pub fn echo_len(&self) -> usize {
+        self.echo_len
+    }
+
+    /// UTF-8 byte cap on the generated text, from the snapshotted `SamplingOptions`.
+    pub fn max_output_bytes(&self) -> Option<usize> {
+        self.sampling.max_output_bytes
+    }
+
+    pub fn trim_trailing_partial(&self) -> bool {
+        self.sampling.trim_trailing_partial
+    }
+
+    pub fn max_instruction_fraction(&self) -> Option<f64> {
+        self.sampling.max_instruction_fraction
+    }
+
+    pub fn include_tokens(&self) -> bool {
+        self.sampling.include_tokens
+    }
+
+    /// `(tokens_generated, max_tokens)` while `session_id` is the generation in
+    /// progress; `None` once it has finished or been superseded.
+    pub fn generation_progress(&self, session_id: &str) -> Option<(usize, usize)> {
+        let current = self.generation_id != 0 && session_id == self.generation_id.to_string();
+        let finished = self.is_stopped() || self.tokens.len() >= self.max_tokens;
+        (current && !finished).then(|| (self.tokens.len(), self.max_tokens))
+    }
+
+    /// Raw vocab pieces (with leading-space markers), unlike `decode`.
+    pub fn token_to_piece(&self, ids: &[u32]) -> Result<Vec<String>, String> {
+        ids.iter()
+            .map(|&id| self.tokenizer.id_to_token(id).ok_or_else(|| format!("Token {} not in vocabulary", id)))
+            .collect()
+    }
+
+    pub fn piece_to_token(&self, piece: &str) -> Option<u32> {
+        self.tokenizer.token_to_id(piece)
+    }
+
+    /// Whether a chunked prefill is paused waiting for `continue_prefill`.
+    pub fn prefill_pending(&self) -> bool {
+        self.pending_prefill.is_some()
+    }
+
+    /// The `n` most likely next tokens as `(piece, probability)`, from the raw
+    /// logits at temperature 1 (no penalties or filters). The forward pass runs
+    /// on a clone of the weights, whose KV cache is a snapshot, so neither
+    /// `tokens` nor the live cache change.
+    pub fn next_token_candidates(&self, n: usize) -> Result<Vec<(String, f32)>, String> {
+        if self.pending_prefill.is_some() {
+            return Err("Prefill incomplete; call continue_prefill first".to_string());
+        }
+        if self.is_context_full() {
+            return Err(format!("Context full ({} tokens)", self.context_length));
+        }
+        let last_token = *self.tokens.last().ok_or("No generation in progress")?;
+
+        let mut model = self.model.clone();
+        let logits = Tensor::from_slice(&[last_token], (1, 1), &self.device)
+            .and_then(|input| model.forward(&input, self.kv_len))
+            .and_then(|logits| logits.squeeze(0)?.to_dtype(DType::F32)?.to_vec1::<f32>())
+            .map_err(|e| e.to_string())?;
+
+        let probs = sampling::softmax(&logits);
+        let mut ranked: Vec<usize> = (0..probs.len()).collect();
+        ranked.sort_unstable_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+        ranked.into_iter()
+            .take(n)
+            .map(|id| {
+                let piece = self.tokenizer.decode(&[id as u32], false)
+                    .map_err(|e| format!("Decode error: {}", e))?;
+                Ok((piece, probs[id]))
+            })
+            .collect()
+    }
+
+    /// Round-trips `SELF_TEST_PROBE` and checks the EOS tokens look like end markers.
+    pub fn tokenizer_self_test(&self) -> Result<SelfTestReport, String> {
+        let encoding = self.tokenizer.encode(SELF_TEST_PROBE, false)
+            .map_err(|e| format!("Encode failed: {}", e))?;
+        let token_ids = encoding.get_ids().to_vec();
+        let decoded = self.tokenizer.decode(&token_ids, false)
+            .map_err(|e| format!("Decode failed: {}", e))?;
+
+        let mut discrepancies = Vec::new();
+        let roundtrip_ok = decoded == SELF_TEST_PROBE;
+        if !roundtrip_ok {
+            let at = SELF_TEST_PROBE.chars().zip(decoded.chars())
+                .take_while(|(a, b)| a == b)
+                .count();
+            discrepancies.push(format!("Round trip differs from character {}: got {:?}", at, decoded));
+        }
+        if let Some(&id) = token_ids.iter().find(|&&id| id as usize >= self.vocab_size) {
+            discrepancies.push(format!("Token {} is outside the model's vocab of {}", id, self.vocab_size));
+        }
+
+        let eos_pieces: Vec<(u32, Option<String>)> = self.eos_tokens.iter()
+            .map(|&id| (id, self.tokenizer.id_to_token(id)))
+            .collect();
+        for (id, piece) in &eos_pieces {
+            match piece {
+                Some(piece) if is_end_marker(piece) => {}
+                Some(piece) => discrepancies.push(format!("EOS token {} is {:?}, not an end marker", id, piece)),
+                None => discrepancies.push(format!("EOS token {} is not in the vocabulary", id)),
+            }
+        }
+        let eos_ok = !eos_pieces.is_empty() && eos_pieces.iter()
+            .all(|(_, piece)| piece.as_deref().is_some_and(is_end_marker));
+
+        Ok(SelfTestReport {
+            probe: SELF_TEST_PROBE.to_string(),
+            token_ids,
+            decoded,
+            roundtrip_ok,
+            eos_pieces,
+            eos_ok,
+            discrepancies,
+        })
+    }
+
     pub fn get_tokenizer(&self) -> Box<dyn TokenizerHandle> {
-        Box::new(Qwen3Tokenizer(self.tokenizer.clone()))
+        Box::new(Qwen3Tokenizer {
+            tokenizer: Arc::clone(&self.tokenizer),
+            vocab_size: self.vocab_size,
+        })
+    }
+
+    /// Re-resolves the stop tokens against the vocab, erroring on any unknown string.
+    pub fn set_eos_tokens(&mut self, names: &[String]) -> Result<(), String> {
+        if names.is_empty() {
+            return Err("At least one EOS token is required".to_string());
+        }
+
+        let mut ids = Vec::with_capacity(names.len());
+        let mut missing = Vec::new();
+        for name in names {
+            match self.tokenizer.token_to_id(name) {
+                Some(id) => ids.push(id),
+                None => missing.push(name.as_str()),
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(format!("EOS tokens not in vocabulary: {}", missing.join(", ")));
+        }
+
+        self.eos_tokens = ids;
+        Ok(())
+    }
+
+    /// Runs `tokens` through the model and keeps the resulting KV cache as the
+    /// (single) cached prefix, replacing any previous one.
+    pub fn cache_prefix(&mut self, tokens: &[u32]) -> Result<String, String> {
+        if tokens.is_empty() {
+            return Err("Prefix must contain at least one token".to_string());
+        }
+        if tokens.len() >= self.context_length {
+            return Err(format!(
+                "Prefix is {} tokens but the context length is {}",
+                tokens.len(), self.context_length
+            ));
+        }
+
+        self.tokens.clear();
+        self.clear_kv_cache();
+        let input = Tensor::new(tokens, &self.device)
+            .and_then(|t| t.unsqueeze(0))
+            .map_err(|e| e.to_string())?;
+        let result = self.model.forward(&input, 0).map_err(|e| e.to_string());
+        let snapshot = self.model.clone();
+        self.clear_kv_cache();
+        result?;
+
+        self.prefix_counter += 1;
+        let id = format!("prefix-{}", self.prefix_counter);
+        self.prefix = Some(CachedPrefix { id: id.clone(), model: snapshot, len: tokens.len() });
+        Ok(id)
+    }
+
+    /// `init_generation` with per-request sampling options.
+    pub fn init_with_options(
+        &mut self,
+        prompt: String,
+        tokenizer: &dyn TokenizerHandle,
+        options: &GenerateOptions,
+    ) -> Result<String, String> {
+        let result = self.start_generation(prompt, tokenizer, options);
+        metrics::record_generation(&result);
+        result
+    }
+
+    /// Restores the cached prefix `prefix_id` and processes only `tokens` after it.
+    pub fn init_with_prefix(
+        &mut self,
+        prefix_id: &str,
+        tokens: &[u32],
+        options: &GenerateOptions,
+    ) -> Result<String, String> {
+        let result = self.start_with_prefix(prefix_id, tokens, options);
+        metrics::record_generation(&result);
+        result
+    }
+
+    fn start_with_prefix(
+        &mut self,
+        prefix_id: &str,
+        tokens: &[u32],
+        options: &GenerateOptions,
+    ) -> Result<String, String> {
+        let prefix = self.prefix.as_ref()
+            .filter(|p| p.id == prefix_id)
+            .ok_or_else(|| format!("Unknown prefix '{}'", prefix_id))?;
+        let (model, len) = (prefix.model.clone(), prefix.len);
+
+        if tokens.is_empty() {
+            return Err("At least one token must follow the prefix".to_string());
+        }
+        if len + tokens.len() > self.context_length {
+            return Err(format!(
+                "Prefix plus prompt is {} tokens but the context length is {}",
+                len + tokens.len(), self.context_length
+            ));
+        }
+
+        self.prepare(options)?;
+        self.model = model;
+        self.kv_len = len;
+        self.prompt_tokens = tokens.to_vec();
+        self.prompt_prefix = Some(prefix_id.to_string());
+        self.prefill(tokens.to_vec(), String::new())
+    }
+
+    /// Re-runs the last prompt with fresh options, skipping tokenization.
+    pub fn init_regeneration(&mut self, options: &GenerateOptions) -> Result<String, String> {
+        let result = self.start_regeneration(options);
+        metrics::record_generation(&result);
+        result
+    }
+
+    fn start_regeneration(&mut self, options: &GenerateOptions) -> Result<String, String> {
+        if self.prompt_tokens.is_empty() {
+            return Err("No previous prompt to regenerate".to_string());
+        }
+        let tokens = self.prompt_tokens.clone();
+        match self.prompt_prefix.clone() {
+            Some(prefix_id) => self.start_with_prefix(&prefix_id, &tokens, options),
+            None => {
+                self.prepare(options)?;
+                self.run_prompt(tokens)
+            }
+        }
+    }
+
+    /// Number of tokens the last prompt was processed as (excluding any cached prefix).
+    pub fn prompt_len(&self) -> usize {
+        self.prompt_tokens.len()
+    }
+
+    /// Applies `options` and clears per-generation state. Callers fill in
+    /// what the request left out with `GenerateOptions::resolve` beforehand.
+    fn prepare(&mut self, options: &GenerateOptions) -> Result<(), String> {
+        crate::policy::check_generation_allowed()?;
+
+        let config = &options.config;
+        crate::config::validate(config)?;
+        options.sampling.validate()?;
+        let temp = if config.temperature <= 0. { None } else { Some(config.temperature) };
+        let top_p = if config.top_p <= 0. || config.top_p >= 1. { None } else { Some(config.top_p) };
+
+        self.sampling = options.sampling.clone();
+        let seed = if self.sampling.random_seed { sampling::random_seed() } else { config.seed };
+
+        self.logits_processor = match (self.sampling.mirostat, temp) {
+            // Mirostat does its own truncation, so sample from everything it keeps
+            (Some(mirostat), Some(temperature)) => {
+                self.mu = 2. * mirostat.tau;
+                LogitsProcessor::from_sampling(seed, Sampling::All { temperature })
+            }
+            _ => LogitsProcessor::new(seed, temp, top_p),
+        };
+        self.seed = seed;
+        self.repeat_penalty = config.repeat_penalty;
+        let max_repeat_last_n = crate::settings::max_repeat_last_n();
+        self.repeat_last_n = config.repeat_last_n.min(max_repeat_last_n);
+        self.repeat_last_n_clamped_from = (config.repeat_last_n > max_repeat_last_n).then_some(config.repeat_last_n);
+        self.max_tokens = config.max_tokens;
+        self.generation_id += 1;
+        // Without a temperature LogitsProcessor already takes the argmax (top_p unused)
+        self.argmax = self.sampling.greedy || temp.is_none();
+        self.tokens.clear();
+        self.echo_len = 0;
+        self.owner = crate::policy::caller();
+        self.cancelled = false;
+        self.json = self.sampling.json_mode.then(JsonState::default);
+        self.logprobs.clear();
+        self.undecoded.clear();
+        self.started_at = ic_cdk::api::time();
+        self.last_step_at = self.started_at;
+        self.instructions = 0;
+        // Stale keys/values from the previous prompt would otherwise leak into this one
+        self.clear_kv_cache();
+        Ok(())
+    }
+
+    fn start_generation(
+        &mut self,
+        prompt: String,
+        tokenizer: &dyn TokenizerHandle,
+        options: &GenerateOptions,
+    ) -> Result<String, String> {
+        self.prepare(options)?;
+
+        let bos = if self.sampling.prepend_bos {
+            Some(self.bos_token.ok_or("prepend_bos is set but the tokenizer has no BOS token")?)
+        } else {
+            None
+        };
+        let mut tokens = encode_prompt(tokenizer, &prompt, bos)?;
+        if self.sampling.truncate_prompt {
+            let budget = self.context_length.saturating_sub(self.max_tokens).max(1);
+            if tokens.len() > budget {
+                let dropped = tokens.len() - budget;
+                tokens.drain(..dropped);
+                ic_dev_kit_rs::telemetry::log_info(&format!("Truncated prompt: dropped {} leading tokens", dropped));
+            }
+        } else if tokens.len() > self.context_length {
+            return Err(format!(
+                "Prompt is {} tokens but the context length is {}",
+                tokens.len(), self.context_length
+            ));
+        }
+
+        self.run_prompt(tokens)
+    }
+
+    /// Prefills `tokens` from an empty cache and remembers them as the prompt.
+    fn run_prompt(&mut self, tokens: Vec<u32>) -> Result<String, String> {
+        // Echo what the model actually saw (after truncation, special tokens included)
+        let echo = if self.sampling.echo {
+            self.tokenizer.decode(&tokens, false).map_err(|e| format!("Decode error: {}", e))?
+        } else {
+            String::new()
+        };
+        self.prompt_tokens = tokens.clone();
+        self.prompt_prefix = None;
+        self.prefill(tokens, echo)
+    }
+
+    /// Runs `tokens` after the current KV cache and samples the first token.
+    ///
+    /// With `settings::prefill_chunk_size` set, all but the last chunk are
+    /// forwarded without sampling, and before each chunk its estimated cost
+    /// (`CostModel::prefill_instructions_per_token`) is checked against the
+    /// instruction budget. If it doesn't fit, the rest is parked for
+    /// `continue_prefill` and an error reports how far the prefill got.
+    fn prefill(&mut self, mut tokens: Vec<u32>, echo: String) -> Result<String, String> {
+        let total = self.pending_prefill.take().map_or(tokens.len(), |p| p.total);
+        let chunk_size = crate::settings::prefill_chunk_size();
+        if chunk_size > 0 {
+            let cost = crate::settings::cost_model();
+            while !tokens.is_empty() {
+                let len = tokens.len().min(chunk_size);
+                let estimate = cost.prefill_instructions_per_token.saturating_mul(len as u64);
+                if ic_cdk::api::performance_counter(0).saturating_add(estimate) > cost.instruction_budget {
+                    let processed = total - tokens.len();
+                    self.pending_prefill = Some(PendingPrefill { remaining: tokens, total, echo });
+                    return Err(format!(
+                        "Partial prefill: {} of {} prompt tokens processed; call continue_prefill",
+                        processed, total
+                    ));
+                }
+                if len == tokens.len() {
+                    break;
+                }
+                let chunk: Vec<u32> = tokens.drain(..len).collect();
+                self.forward_only(&chunk).map_err(|e| e.to_string())?;
+            }
+        }
+
+        let first = self.process(&tokens).map_err(|e| e.to_string())?;
+        self.echo_len = echo.len();
+        Ok(echo + &first)
+    }
+
+    /// Resumes a chunked prefill that paused on the instruction budget,
+    /// returning the text so far (as from `init_generation`) once it completes.
+    /// If it pauses again, `prefill_progress` says how far it got. Only the
+    /// caller that started the generation may resume it.
+    pub fn continue_prefill(&mut self) -> Result<String, String> {
+        let pending = self.pending_prefill.as_ref().ok_or("No prefill in progress")?;
+        if ic_cdk::api::msg_caller() != self.owner {
+            return Err("Only the caller that started a generation can continue it".to_string());
+        }
+        let (remaining, echo) = (pending.remaining.clone(), pending.echo.clone());
+        self.prefill(remaining, echo)
+    }
+
+    /// `(processed, total)` prompt tokens of a paused chunked prefill.
+    pub fn prefill_progress(&self) -> Option<(usize, usize)> {
+        self.pending_prefill.as_ref().map(|p| (p.total - p.remaining.len(), p.total))
+    }
+
+    fn next_token(&mut self) -> Result<String, String> {
+        if self.cancelled {
+            return Err("Generation cancelled".to_string());
+        }
+        if self.is_context_full() {
+            return Err(format!("Context full ({} tokens)", self.context_length));
+        }
+        if self.pending_prefill.is_some() {
+            return Err("Prefill incomplete; call continue_prefill first".to_string());
+        }
+        let last_token = *self.tokens.last().ok_or("No tokens generated")?;
+        self.process(&[last_token]).map_err(|e| e.to_string())
+    }
+
+    /// Runs one throwaway forward pass so lazy allocations happen before the first real request.
+    pub fn warmup(&mut self) -> Result<(), String> {
+        let tokens = self.tokenizer.encode("Hello, world", true)
+            .map_err(|e| format!("Encode error: {}", e))?
+            .get_ids()
+            .to_vec();
+
+        self.tokens.clear();
+        self.clear_kv_cache();
+        let result = self.process(&tokens).map(|_| ()).map_err(|e| e.to_string());
+        self.tokens.clear();
+        self.clear_kv_cache();
+        result
+    }
+
+    /// Also drops a paused prefill, which could only resume onto the old cache.
+    fn clear_kv_cache(&mut self) {
+        self.model.clear_kv_cache();
+        self.kv_len = 0;
+        self.pending_prefill = None;
     }
 
     fn process(&mut self, tokens: &[u32]) -> candle_core::Result<String> {
-        use candle_core::{DType, Device, Tensor};
+        let start = ic_cdk::api::performance_counter(0);
+        let result = self.step(tokens);
+        let instructions = ic_cdk::api::performance_counter(0).saturating_sub(start);
+        self.instructions += instructions;
+        metrics::record_instructions(instructions);
+        self.last_step_at = ic_cdk::api::time();
+        result
+    }
+
+    /// Extends the KV cache with `tokens` without sampling (a non-final prefill chunk).
+    fn forward_only(&mut self, tokens: &[u32]) -> candle_core::Result<()> {
+        let start = ic_cdk::api::performance_counter(0);
+        let input = Tensor::from_slice(tokens, (1, tokens.len()), &self.device)?;
+        let result = self.model.forward(&input, self.kv_len);
+        let instructions = ic_cdk::api::performance_counter(0).saturating_sub(start);
+        self.instructions += instructions;
+        metrics::record_instructions(instructions);
+        result?;
+        self.kv_len += tokens.len();
+        Ok(())
+    }
+
+    /// Text completed by `token`: empty while the held-back tokens still end
+    /// mid-character (decoded as U+FFFD), everything held once they don't.
+    /// The remainder is flushed on EOS, at `max_tokens` and when the context is full.
+    fn decode_streamed(&mut self, token: u32) -> candle_core::Result<String> {
+        self.undecoded.push(token);
+        let skip_special = !self.sampling.keep_special_tokens;
+        let text = self.tokenizer.decode(&self.undecoded, skip_special)
+            .map_err(|e| candle_core::Error::Msg(format!("{:?}", e)))?;
 
-        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
-        let logits = self.model.forward(&input, self.tokens.len())?.squeeze(0)?.to_dtype(DType::F32)?;
+        // A character is at most 4 bytes, so 4 held tokens that still don't
+        // decode never will
+        let flush = self.undecoded.len() >= 4
+            || self.eos_tokens.contains(&token)
+            || self.tokens.len() >= self.max_tokens
+            || self.is_context_full();
+        if text.ends_with('\u{FFFD}') && !flush {
+            return Ok(String::new());
+        }
+        self.undecoded.clear();
+        Ok(text)
+    }
+
+    fn step(&mut self, tokens: &[u32]) -> candle_core::Result<String> {
+        // Built as (1, n) in one go; no separate unsqueeze per decode step
+        let input = Tensor::from_slice(tokens, (1, tokens.len()), &self.device)?;
+        let logits = self.model.forward(&input, self.kv_len)?.squeeze(0)?.to_dtype(DType::F32)?;
+        self.kv_len += tokens.len();
 
-        let logits = if self.repeat_penalty != 1. {
+        // repeat_last_n == 0 disables the penalty rather than penalizing an empty window
+        let logits = if self.repeat_penalty != 1. && self.repeat_last_n > 0 {
             let start = self.tokens.len().saturating_sub(self.repeat_last_n);
             candle_transformers::utils::apply_repeat_penalty(&logits, self.repeat_penalty, &self.tokens[start..])?
         } else {
             logits
         };
 
-        let next_token = self.logits_processor.sample(&logits)?;
+        let logits = if self.tokens.len() < self.sampling.min_tokens {
+            sampling::suppress(logits, &self.eos_tokens)?
+        } else {
+            logits
+        };
+
+        let logits = match &self.json {
+            Some(json) => {
+                let tokenizer = &self.tokenizer;
+                let skip_special = !self.sampling.keep_special_tokens;
+                json.mask(logits, &self.eos_tokens, |id| tokenizer.decode(&[id], skip_special).ok())?
+            }
+            None => logits,
+        };
+
+        let logits = sampling::apply_filters(logits, &self.sampling)?;
+
+        let next_token = if self.argmax {
+            sampling::argmax(&logits)?
+        } else if let Some(mirostat) = self.sampling.mirostat {
+            let mut values = logits.to_vec1::<f32>()?;
+            sampling::mask_mirostat(&mut values, self.mu);
+            let token = self.logits_processor.sample(&Tensor::new(values.as_slice(), logits.device())?)?;
+            self.mu -= mirostat.eta * (sampling::surprise(&values, token) - mirostat.tau);
+            token
+        } else {
+            self.logits_processor.sample(&logits)?
+        };
         self.tokens.push(next_token);
+        if self.capture_logprobs {
+            self.logprobs.push(sampling::log_prob(&logits, next_token)?);
+        }
+
+        let text = self.decode_streamed(next_token)?;
+        if let Some(json) = &mut self.json {
+            if !self.eos_tokens.contains(&next_token) && !json.push_str(&text) {
+                return Err(candle_core::Error::Msg(format!("JSON mode: {text:?} breaks the JSON output")));
+            }
+        }
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One token per whitespace-separated word; no weights needed.
+    struct WordTokenizer;
+
+    impl TokenizerHandle for WordTokenizer {
+        fn encode(&self, text: &str) -> Result<Vec<u32>, String> {
+            Ok(text.split_whitespace().map(|word| word.len() as u32).collect())
+        }
+
+        fn decode(&self, tokens: &[u32]) -> Result<String, String> {
+            Ok(tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(" "))
+        }
+
+        fn vocab_size(&self) -> usize {
+            64
+        }
+    }
 
-        self.tokenizer.decode(&[next_token], false)
-            .map_err(|e| candle_core::Error::Msg(format!("{:?}", e)))
+    #[test]
+    fn empty_prompt_is_rejected() {
+        for prompt in ["", "   ", "\n\t"] {
+            for bos in [None, Some(0)] {
+                assert_eq!(
+                    encode_prompt(&WordTokenizer, prompt, bos),
+                    Err("prompt produced no tokens".to_string()),
+                    "prompt {prompt:?}, bos {bos:?}"
+                );
+            }
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn bos_is_prepended_once() {
+        assert_eq!(encode_prompt(&WordTokenizer, "hi there", Some(0)), Ok(vec![0, 2, 5]));
+        assert_eq!(encode_prompt(&WordTokenizer, "hi there", Some(2)), Ok(vec![2, 5]));
+        assert_eq!(encode_prompt(&WordTokenizer, "hi there", None), Ok(vec![2, 5]));
+    }
+}
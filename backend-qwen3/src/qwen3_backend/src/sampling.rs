@@ -0,0 +1,330 @@
+//! Decoding options beyond ic-dev-kit's `GenerationConfig`
+//!
+//! `GenerationConfig` is defined upstream, so the extra knobs live here. Local
+//! endpoints take them per request; requests without any fall back to the
+//! canister-wide options set with `set_sampling_options`.
+
+use std::cell::RefCell;
+
+use candid::CandidType;
+use candle_core::Tensor;
+use ic_dev_kit_rs::text_generation::GenerationConfig;
+use serde::Deserialize;
+
+use crate::storage::{load_state, save_state};
+
+const SAMPLING_OPTIONS_KEY: &str = "__sampling_options__";
+
+/// Mirostat v2: keeps the per-token surprise near `tau` bits by adapting a
+/// truncation threshold `mu` with learning rate `eta`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct MirostatConfig {
+    pub tau: f32,
+    pub eta: f32,
+}
+
+/// `Default` comes from `config::GenerationConfigBuilder`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SamplingOptions {
+    /// Take the argmax of the logits, ignoring temperature/top_p, for bit-for-bit reproducible output
+    pub greedy: bool,
+    /// Keep only the last `context_length - max_tokens` prompt tokens instead of
+    /// rejecting prompts that don't fit
+    pub truncate_prompt: bool,
+    /// Drop tokens whose probability is below `min_p` times the top token's
+    pub min_p: Option<f64>,
+    /// Locally-typical sampling: keep the tokens closest to the expected surprise
+    /// until their mass reaches `typical_p`
+    pub typical_p: Option<f64>,
+    /// Derive each generation's seed from the time and caller instead of `config.seed`
+    pub random_seed: bool,
+    /// Prepend the decoded prompt to the generated text, like OpenAI's `echo`
+    pub echo: bool,
+    /// Replaces top_p with Mirostat v2 truncation (temperature still applies)
+    pub mirostat: Option<MirostatConfig>,
+    /// Suppress EOS until this many tokens have been generated
+    pub min_tokens: usize,
+    /// Constrain output to valid JSON (see `json_mode`)
+    pub json_mode: bool,
+    /// Stop once the generated text reaches this many UTF-8 bytes, cutting the
+    /// last token at a character boundary
+    pub max_output_bytes: Option<usize>,
+    /// Prepend the BOS token when the encoded prompt doesn't already start
+    /// with it. Off follows the tokenizer's own post-processing.
+    pub prepend_bos: bool,
+    /// Keep control tokens such as `<|im_end|>` in the generated text (for
+    /// debugging); by default they are decoded to nothing
+    pub keep_special_tokens: bool,
+    /// When output is cut off (`Length`, `MaxBytes`, `InstructionBudget`),
+    /// drop a trailing partial word and whitespace. Only space-delimited scripts
+    /// are trimmed (CJK, Thai etc. are kept); see `generation::trim_partial`
+    pub trim_trailing_partial: bool,
+    /// Stop once the message has used this fraction (0–1] of the instruction
+    /// budget, instead of relying on `max_tokens` alone
+    pub max_instruction_fraction: Option<f64>,
+    /// Return the generated token IDs in `Completion::generated_tokens`
+    pub include_tokens: bool,
+}
+
+impl SamplingOptions {
+    /// Rejects values outside the ranges the filters are defined for.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(min_p) = self.min_p.filter(|p| !(0. ..=1.).contains(p)) {
+            return Err(format!("min_p must be in [0, 1], got {}", min_p));
+        }
+        if let Some(typical_p) = self.typical_p.filter(|&p| !(p > 0. && p <= 1.)) {
+            return Err(format!("typical_p must be in (0, 1], got {}", typical_p));
+        }
+        if let Some(mirostat) = self.mirostat {
+            if !(mirostat.tau.is_finite() && mirostat.tau > 0.) {
+                return Err(format!("mirostat tau must be > 0, got {}", mirostat.tau));
+            }
+            if !(mirostat.eta.is_finite() && mirostat.eta > 0.) {
+                return Err(format!("mirostat eta must be > 0, got {}", mirostat.eta));
+            }
+        }
+        if let Some(fraction) = self.max_instruction_fraction.filter(|&f| !(f > 0. && f <= 1.)) {
+            return Err(format!("max_instruction_fraction must be in (0, 1], got {}", fraction));
+        }
+        Ok(())
+    }
+}
+
+/// Everything one generation runs with.
+#[derive(Clone, Debug)]
+pub struct GenerateOptions {
+    pub config: GenerationConfig,
+    pub sampling: SamplingOptions,
+}
+
+impl GenerateOptions {
+    /// Fills what the request left out from the stored defaults.
+    pub fn resolve(config: Option<GenerationConfig>, sampling: Option<SamplingOptions>) -> Self {
+        Self {
+            config: config.unwrap_or_else(crate::settings::generation_defaults),
+            sampling: sampling.unwrap_or_else(options),
+        }
+    }
+}
+
+thread_local! {
+    static SAMPLING_OPTIONS: RefCell<SamplingOptions> = RefCell::new(SamplingOptions::default());
+}
+
+/// Reloads the persisted options after an upgrade.
+pub fn restore() {
+    let options = load_state(SAMPLING_OPTIONS_KEY).unwrap_or_default();
+    SAMPLING_OPTIONS.with(|o| *o.borrow_mut() = options);
+}
+
+/// Canister-wide options for requests that carry none.
+pub fn options() -> SamplingOptions {
+    SAMPLING_OPTIONS.with(|o| o.borrow().clone())
+}
+
+/// A per-message seed mixing the current time with the caller's principal.
+pub fn random_seed() -> u64 {
+    let caller = crate::policy::caller();
+    caller.as_slice().iter()
+        .fold(ic_cdk::api::time(), |seed, &b| seed.rotate_left(8) ^ b as u64)
+}
+
+/// Sets the logits of `ids` to `-inf`.
+pub fn suppress(logits: Tensor, ids: &[u32]) -> candle_core::Result<Tensor> {
+    let mut values = logits.to_vec1::<f32>()?;
+    for &id in ids {
+        if let Some(logit) = values.get_mut(id as usize) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+    Tensor::new(values, logits.device())
+}
+
+/// Masks logits excluded by `min_p` and then `typical_p` to `-inf`.
+///
+/// Both filters see probabilities at temperature 1 and run before
+/// `LogitsProcessor`, so the overall order is min_p → typical_p → temperature → top_p.
+/// With neither set the logits are returned untouched.
+pub fn apply_filters(logits: Tensor, options: &SamplingOptions) -> candle_core::Result<Tensor> {
+    if options.min_p.is_none() && options.typical_p.is_none() {
+        return Ok(logits);
+    }
+
+    let mut values = logits.to_vec1::<f32>()?;
+    if let Some(min_p) = options.min_p {
+        mask_min_p(&mut values, min_p as f32);
+    }
+    if let Some(typical_p) = options.typical_p {
+        mask_typical(&mut values, typical_p as f32);
+    }
+    Tensor::new(values, logits.device())
+}
+
+/// Numerically stable softmax over the finite logits.
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits.iter().map(|&l| (l - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+fn mask_min_p(logits: &mut [f32], min_p: f32) {
+    let probs = softmax(logits);
+    let threshold = min_p * probs.iter().copied().fold(0., f32::max);
+    for (logit, p) in logits.iter_mut().zip(probs) {
+        if p < threshold {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+fn mask_typical(logits: &mut [f32], typical_p: f32) {
+    let probs = softmax(logits);
+    let entropy: f32 = probs.iter()
+        .filter(|&&p| p > 0.)
+        .map(|&p| -p * p.ln())
+        .sum();
+
+    // Rank by distance between each token's surprise and the entropy
+    let mut ranked: Vec<(usize, f32)> = probs.iter().enumerate()
+        .filter(|&(_, &p)| p > 0.)
+        .map(|(i, &p)| (i, (-p.ln() - entropy).abs()))
+        .collect();
+    ranked.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let mut keep = vec![false; logits.len()];
+    let mut mass = 0.;
+    for (i, _) in ranked {
+        keep[i] = true;
+        mass += probs[i];
+        if mass >= typical_p {
+            break;
+        }
+    }
+    for (logit, keep) in logits.iter_mut().zip(keep) {
+        if !keep {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// Masks tokens whose surprise `-log2(p)` exceeds `mu`, always keeping the most likely one.
+pub fn mask_mirostat(logits: &mut [f32], mu: f32) {
+    let probs = softmax(logits);
+    let top = probs.iter().copied().fold(0., f32::max);
+    for (logit, p) in logits.iter_mut().zip(probs) {
+        if p < top && -p.log2() > mu {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// Surprise in bits of `token` under the (masked) distribution `logits`.
+pub fn surprise(logits: &[f32], token: u32) -> f32 {
+    -softmax(logits)[token as usize].log2()
+}
+
+/// Natural-log probability of `token` under softmax(`logits`).
+pub fn log_prob(logits: &Tensor, token: u32) -> candle_core::Result<f32> {
+    let values = logits.to_vec1::<f32>()?;
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = values.iter().map(|&l| (l - max).exp()).sum();
+    Ok(values[token as usize] - max - sum.ln())
+}
+
+/// Index of the highest logit in a 1-D tensor.
+pub fn argmax(logits: &Tensor) -> candle_core::Result<u32> {
+    logits.argmax(0)?.to_scalar::<u32>()
+}
+
+/// Options used by requests that don't pass their own; persists across upgrades.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_sampling_options(options: SamplingOptions) -> Result<(), String> {
+    options.validate()?;
+    save_state(SAMPLING_OPTIONS_KEY, &options);
+    SAMPLING_OPTIONS.with(|o| *o.borrow_mut() = options);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_sampling_options() -> SamplingOptions {
+    options()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logits(probs: &[f32]) -> Vec<f32> {
+        probs.iter().map(|p| p.ln()).collect()
+    }
+
+    fn kept(logits: &[f32]) -> Vec<bool> {
+        logits.iter().map(|l| l.is_finite()).collect()
+    }
+
+    #[test]
+    fn min_p_is_relative_to_the_top_token() {
+        let mut values = logits(&[0.5, 0.3, 0.15, 0.05]);
+        mask_min_p(&mut values, 0.2);
+        assert_eq!(kept(&values), [true, true, true, false]);
+
+        let mut values = logits(&[0.5, 0.3, 0.15, 0.05]);
+        mask_min_p(&mut values, 1.);
+        assert_eq!(kept(&values), [true, false, false, false]);
+    }
+
+    #[test]
+    fn typical_keeps_tokens_nearest_the_entropy() {
+        // The two 0.3 tokens are closer to the expected surprise than the 0.4 one
+        let mut values = logits(&[0.4, 0.3, 0.3]);
+        mask_typical(&mut values, 0.5);
+        assert_eq!(kept(&values), [false, true, true]);
+
+        let mut values = logits(&[0.4, 0.3, 0.3]);
+        mask_typical(&mut values, 1.);
+        assert_eq!(kept(&values), [true, true, true]);
+    }
+
+    #[test]
+    fn mirostat_masks_by_surprise_but_keeps_the_top_token() {
+        // Surprises of 1, 2, 3 and 3 bits
+        let mut values = logits(&[0.5, 0.25, 0.125, 0.125]);
+        mask_mirostat(&mut values, 2.5);
+        assert_eq!(kept(&values), [true, true, false, false]);
+
+        let mut values = logits(&[0.5, 0.25, 0.125, 0.125]);
+        mask_mirostat(&mut values, 0.);
+        assert_eq!(kept(&values), [true, false, false, false]);
+        assert!(surprise(&values, 0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn filters_apply_only_when_set() {
+        let device = candle_core::Device::Cpu;
+        let values = logits(&[0.5, 0.3, 0.15, 0.05]);
+        let tensor = Tensor::new(values.as_slice(), &device).unwrap();
+
+        let untouched = apply_filters(tensor.clone(), &SamplingOptions::default()).unwrap();
+        assert_eq!(untouched.to_vec1::<f32>().unwrap(), values);
+
+        let options = SamplingOptions { min_p: Some(0.2), ..SamplingOptions::default() };
+        let filtered = apply_filters(tensor, &options).unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(kept(&filtered), [true, true, true, false]);
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_options() {
+        assert!(SamplingOptions::default().validate().is_ok());
+        let invalid = [
+            SamplingOptions { min_p: Some(1.5), ..SamplingOptions::default() },
+            SamplingOptions { typical_p: Some(0.), ..SamplingOptions::default() },
+            SamplingOptions { mirostat: Some(MirostatConfig { tau: 0., eta: 0.1 }), ..SamplingOptions::default() },
+            SamplingOptions { mirostat: Some(MirostatConfig { tau: 5., eta: f32::NAN }), ..SamplingOptions::default() },
+            SamplingOptions { max_instruction_fraction: Some(1.5), ..SamplingOptions::default() },
+        ];
+        for options in invalid {
+            assert!(options.validate().is_err(), "{:?}", options);
+        }
+    }
+}
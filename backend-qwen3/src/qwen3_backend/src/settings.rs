@@ -0,0 +1,166 @@
+//! Operator settings that persist in stable storage across upgrades
+
+use std::cell::{Cell, RefCell};
+
+use candid::CandidType;
+use ic_dev_kit_rs::text_generation::GenerationConfig;
+use serde::Deserialize;
+
+use crate::storage::{load_state, save_state};
+
+const GENERATION_DEFAULTS_KEY: &str = "__generation_defaults__";
+const AUTO_RELOAD_KEY: &str = "__auto_reload__";
+const COST_MODEL_KEY: &str = "__cost_model__";
+const MAX_REPEAT_LAST_N_KEY: &str = "__max_repeat_last_n__";
+const PREFILL_CHUNK_SIZE_KEY: &str = "__prefill_chunk_size__";
+
+/// Default cap on `repeat_last_n`; the penalty scans that many tokens every step.
+const DEFAULT_MAX_REPEAT_LAST_N: usize = 1024;
+
+/// Calibrated instruction costs (see `benches/inference_bench.rs`) used to size `max_tokens`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CostModel {
+    /// Prefill cost per prompt token
+    pub prefill_instructions_per_token: u64,
+    /// Cost of one decode step
+    pub decode_instructions_per_token: u64,
+    /// Instructions a single generate call may spend
+    pub instruction_budget: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            prefill_instructions_per_token: 300_000_000,
+            decode_instructions_per_token: 1_000_000_000,
+            instruction_budget: 30_000_000_000,
+        }
+    }
+}
+
+thread_local! {
+    static GENERATION_DEFAULTS: RefCell<Option<GenerationConfig>> = const { RefCell::new(None) };
+    static AUTO_RELOAD: Cell<bool> = const { Cell::new(false) };
+    static COST_MODEL: RefCell<CostModel> = RefCell::new(CostModel::default());
+    static MAX_REPEAT_LAST_N: Cell<usize> = const { Cell::new(DEFAULT_MAX_REPEAT_LAST_N) };
+    /// 0 prefills the whole prompt in one forward pass
+    static PREFILL_CHUNK_SIZE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Reloads cached settings after an upgrade.
+pub fn restore() {
+    let defaults = load_state::<GenerationConfig>(GENERATION_DEFAULTS_KEY);
+    GENERATION_DEFAULTS.with(|d| *d.borrow_mut() = defaults);
+    AUTO_RELOAD.with(|a| a.set(load_state(AUTO_RELOAD_KEY).unwrap_or(false)));
+    let cost_model = load_state(COST_MODEL_KEY).unwrap_or_default();
+    COST_MODEL.with(|c| *c.borrow_mut() = cost_model);
+    let max_repeat_last_n = load_state(MAX_REPEAT_LAST_N_KEY).unwrap_or(DEFAULT_MAX_REPEAT_LAST_N);
+    MAX_REPEAT_LAST_N.with(|m| m.set(max_repeat_last_n));
+    PREFILL_CHUNK_SIZE.with(|p| p.set(load_state(PREFILL_CHUNK_SIZE_KEY).unwrap_or(0)));
+}
+
+pub fn max_repeat_last_n() -> usize {
+    MAX_REPEAT_LAST_N.with(|m| m.get())
+}
+
+pub fn prefill_chunk_size() -> usize {
+    PREFILL_CHUNK_SIZE.with(|p| p.get())
+}
+
+pub fn cost_model() -> CostModel {
+    COST_MODEL.with(|c| c.borrow().clone())
+}
+
+/// Whether `post_upgrade` should load the model from stable storage.
+pub fn auto_reload() -> bool {
+    AUTO_RELOAD.with(|a| a.get())
+}
+
+/// Canister-wide sampling defaults (`set_sampling_defaults`), falling back to
+/// `GenerationConfig::default()`. Local endpoints use these for a `None` config.
+pub fn generation_defaults() -> GenerationConfig {
+    GENERATION_DEFAULTS.with(|d| d.borrow().clone()).unwrap_or_default()
+}
+
+/// Sampling parameters for requests that carry no config; persists across upgrades.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_sampling_defaults(config: GenerationConfig) -> Result<(), String> {
+    crate::config::validate(&config)?;
+    save_state(GENERATION_DEFAULTS_KEY, &config);
+    GENERATION_DEFAULTS.with(|d| *d.borrow_mut() = Some(config));
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_sampling_defaults() -> GenerationConfig {
+    generation_defaults()
+}
+
+/// Original name of `set_sampling_defaults`, kept for existing callers.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_default_generation_config(config: GenerationConfig) -> Result<(), String> {
+    set_sampling_defaults(config)
+}
+
+/// Original name of `get_sampling_defaults`.
+#[ic_cdk::query]
+fn get_default_generation_config() -> GenerationConfig {
+    generation_defaults()
+}
+
+/// Opt in to reloading the model in `post_upgrade`. Loading is heavy, so this is off by default.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_auto_reload(enabled: bool) {
+    save_state(AUTO_RELOAD_KEY, &enabled);
+    AUTO_RELOAD.with(|a| a.set(enabled));
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_max_repeat_last_n(n: usize) {
+    save_state(MAX_REPEAT_LAST_N_KEY, &n);
+    MAX_REPEAT_LAST_N.with(|m| m.set(n));
+}
+
+/// Prefill long prompts `size` tokens per forward pass, pausing between
+/// chunks when the instruction budget runs low; 0 turns chunking off.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_prefill_chunk_size(size: usize) {
+    save_state(PREFILL_CHUNK_SIZE_KEY, &size);
+    PREFILL_CHUNK_SIZE.with(|p| p.set(size));
+}
+
+/// Instructions one generate call may spend before it stops, leaving margin
+/// under the subnet's per-message limit. Persisted as part of the cost model.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_instruction_budget(n: u64) {
+    let cost_model = COST_MODEL.with(|c| {
+        let mut c = c.borrow_mut();
+        c.instruction_budget = n;
+        c.clone()
+    });
+    save_state(COST_MODEL_KEY, &cost_model);
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_cost_model(cost_model: CostModel) -> Result<(), String> {
+    if cost_model.decode_instructions_per_token == 0 {
+        return Err("decode_instructions_per_token must be positive".to_string());
+    }
+    save_state(COST_MODEL_KEY, &cost_model);
+    COST_MODEL.with(|c| *c.borrow_mut() = cost_model);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_cost_model() -> CostModel {
+    cost_model()
+}
+
+/// How many tokens should fit in the instruction budget after a `prompt_len`-token prefill.
+#[ic_cdk::query]
+fn estimate_max_tokens(prompt_len: usize) -> usize {
+    let cost = cost_model();
+    let prefill = cost.prefill_instructions_per_token.saturating_mul(prompt_len as u64);
+    let remaining = cost.instruction_budget.saturating_sub(prefill);
+    (remaining / cost.decode_instructions_per_token) as usize
+}
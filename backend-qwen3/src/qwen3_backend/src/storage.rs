@@ -0,0 +1,1401 @@
+//! Upload buffers and stable-storage endpoints backed by `REGISTRIES`
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+use candid::CandidType;
+use serde::de::DeserializeOwned;
+use ic_stable_structures::{Memory as _, StableBTreeMap};
+use serde::Deserialize;
+
+use crate::qwen3::{self, ModelFormat};
+use crate::{Memory, REGISTRIES};
+
+#[cfg(feature = "canbench-rs")]
+#[path = "../benches/storage_bench.rs"]
+mod storage_bench;
+
+/// Machine-readable failures of the upload/storage API.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum StorageError {
+    KeyNotFound(String),
+    KeyExists(String),
+    EmptyBuffer,
+    MissingChunks(Vec<u32>),
+    UnexpectedChunks(Vec<u32>),
+    ChecksumMismatch { expected: String, actual: String },
+    QuotaExceeded { requested: u64, limit: u64 },
+    OutOfRange { offset: u64, size: u64 },
+    SizeMismatch { expected: u64, actual: u64 },
+    Compression(String),
+    InvalidRequest(String),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KeyNotFound(key) => write!(f, "Key '{}' not found", key),
+            Self::KeyExists(key) => write!(f, "Key '{}' already exists; pass overwrite = true to replace it", key),
+            Self::EmptyBuffer => write!(f, "Buffer is empty"),
+            Self::MissingChunks(ids) => write!(f, "Missing chunks: {:?}", ids),
+            Self::UnexpectedChunks(ids) => write!(f, "Unexpected chunks: {:?}", ids),
+            Self::ChecksumMismatch { expected, actual } => write!(f, "Checksum mismatch: expected {}, got {}", expected, actual),
+            Self::QuotaExceeded { requested, limit } => write!(f, "Would hold {} bytes, limit is {}", requested, limit),
+            Self::OutOfRange { offset, size } => write!(f, "Offset {} past end of value ({} bytes)", offset, size),
+            Self::SizeMismatch { expected, actual } => write!(f, "Expected {} bytes, got {}", expected, actual),
+            Self::Compression(msg) => write!(f, "Compression error: {}", msg),
+            Self::InvalidRequest(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MemoryStats {
+    pub heap_buffer_bytes: u64,
+    pub parallel_buffer_bytes: u64,
+    /// Bytes actually held in stable storage, as compressed, across every value
+    pub stable_total_bytes: u64,
+    /// `stable_total_bytes` before compression, where sidecars record it
+    pub stable_logical_bytes: u64,
+    pub model_loaded: bool,
+    /// Stable memory size in 64 KiB WebAssembly pages
+    pub wasm_pages: u64,
+}
+
+/// Structured counterpart of `storage_status`, for dashboards.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StableSummary {
+    /// Every stored value (a segmented one under its base key) with its size
+    /// in bytes, uncompressed where a sidecar records it
+    pub entries: Vec<(String, usize)>,
+    pub total_bytes: u64,
+    /// Stable memory size in 64 KiB WebAssembly pages
+    pub stable_pages: u64,
+}
+
+/// Sidecar record written next to every saved value, under `<key>__meta`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KeyMetadata {
+    /// `None` for values that aren't recognizable weights (tokenizers, configs)
+    pub format: Option<ModelFormat>,
+    pub architecture: Option<String>,
+    pub quantization: Option<String>,
+    pub compression: Compression,
+    /// Nanoseconds since the epoch
+    pub uploaded_at: u64,
+    /// Hex SHA-256 of the uncompressed bytes, recorded when the save verified
+    /// an `expected_sha256`; empty otherwise
+    pub sha256: String,
+    pub size: u64,
+    /// Uncompressed length of each segment, in order, for values stored as segments
+    pub segment_sizes: Option<Vec<u64>>,
+    /// Bytes as stored (after compression), across all segments
+    pub stored_size: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IntegrityResult {
+    pub key: String,
+    pub ok: bool,
+    /// Digest recorded in the sidecar
+    pub expected: String,
+    /// `None` when the value couldn't be read back
+    pub actual: Option<String>,
+}
+
+/// One page of `verify_stable_integrity`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IntegrityReport {
+    pub results: Vec<IntegrityResult>,
+    /// Values in this page with no recorded digest, which weren't hashed
+    pub skipped: Vec<String>,
+    /// Pass as `start_after` for the next page; `None` once every key is covered
+    pub next: Option<String>,
+}
+
+/// What the uploader announced in `begin_upload`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+struct UploadManifest {
+    expected_bytes: u64,
+    expected_chunks: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UploadProgress {
+    pub received_bytes: u64,
+    pub expected_bytes: u64,
+    pub received_chunks: u32,
+    pub expected_chunks: u32,
+    /// 0 without a manifest
+    pub percent: f64,
+}
+
+impl From<StorageError> for String {
+    fn from(e: StorageError) -> Self {
+        e.to_string()
+    }
+}
+
+/// Largest slice returned per call, kept under the IC reply limit.
+const MAX_RESPONSE_BYTES: usize = 1_900_000;
+
+const DEFAULT_MAX_BUFFER_BYTES: u64 = 1 << 30;
+
+const MAX_UPLOAD_BYTES_KEY: &str = "__max_upload_bytes__";
+
+/// Prefix marking a stored value as a zstd frame.
+const ZSTD_HEADER: &[u8] = b"ICZSTD01";
+
+/// How a value is encoded when written to stable storage.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+impl Compression {
+    fn ensure_supported(self) -> Result<(), StorageError> {
+        if self == Self::Zstd && !cfg!(feature = "compression") {
+            return Err(StorageError::Compression("built without the `compression` feature".to_string()));
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// Sequential upload buffer
+    static BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+
+    /// Parallel upload chunks, keyed by chunk ID. With dedup on, identical
+    /// chunks share one allocation.
+    static BUFFER_MAP: RefCell<HashMap<u32, Rc<Vec<u8>>>> = RefCell::new(HashMap::new());
+
+    /// Store identical parallel chunks once (content-addressed by SHA-256)
+    static DEDUP_CHUNKS: Cell<bool> = const { Cell::new(false) };
+
+    /// Dedup index from chunk digest to the shared blob; stale entries are
+    /// skipped and dropped with the buffer
+    static CHUNK_BLOBS: RefCell<HashMap<[u8; 32], Weak<Vec<u8>>>> = RefCell::new(HashMap::new());
+
+    /// Cap on `BUFFER` plus `BUFFER_MAP`, so uploads fail cleanly instead of trapping
+    static MAX_BUFFER_BYTES: Cell<u64> = const { Cell::new(DEFAULT_MAX_BUFFER_BYTES) };
+
+    static MANIFEST: Cell<Option<UploadManifest>> = const { Cell::new(None) };
+
+    /// Chunks appended to `BUFFER` since it was last cleared
+    static SEQUENTIAL_CHUNKS: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Reloads the upload quota after an upgrade; the buffers themselves don't survive one.
+pub fn restore() {
+    let limit = load_state(MAX_UPLOAD_BYTES_KEY).unwrap_or(DEFAULT_MAX_BUFFER_BYTES);
+    MAX_BUFFER_BYTES.with(|m| m.set(limit));
+}
+
+/// Bytes currently held across both upload buffers.
+fn buffered_bytes() -> u64 {
+    let sequential = BUFFER.with(|b| b.borrow().len() as u64);
+    sequential + parallel_heap_bytes()
+}
+
+/// Heap held by the parallel chunks, counting each shared blob once.
+fn parallel_heap_bytes() -> u64 {
+    BUFFER_MAP.with(|m| {
+        let m = m.borrow();
+        let mut seen = HashSet::new();
+        m.values()
+            .filter(|c| seen.insert(Rc::as_ptr(c)))
+            .map(|c| c.len() as u64)
+            .sum()
+    })
+}
+
+/// Logical size of the parallel upload, as it will be consolidated.
+fn parallel_total_bytes() -> u64 {
+    BUFFER_MAP.with(|m| m.borrow().values().map(|c| c.len() as u64).sum())
+}
+
+/// Errors if adding `incoming` bytes (after `released` are dropped) would exceed the cap.
+fn check_upload_quota(incoming: usize, released: usize) -> Result<(), StorageError> {
+    let limit = MAX_BUFFER_BYTES.with(|m| m.get());
+    let total = (buffered_bytes() + incoming as u64).saturating_sub(released as u64);
+    if total > limit {
+        return Err(StorageError::QuotaExceeded { requested: total, limit });
+    }
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_max_upload_bytes(n: u64) {
+    save_state(MAX_UPLOAD_BYTES_KEY, &n);
+    MAX_BUFFER_BYTES.with(|m| m.set(n));
+}
+
+/// Announces the size of the next upload for `upload_progress` and the
+/// consolidation cross-check, replacing any previous manifest.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn begin_upload(expected_total_bytes: u64, chunk_count: u32) {
+    MANIFEST.with(|m| m.set(Some(UploadManifest {
+        expected_bytes: expected_total_bytes,
+        expected_chunks: chunk_count,
+    })));
+}
+
+/// Drops the manifest once the upload it described has been consolidated or
+/// saved (or abandoned), so the next upload isn't checked against it.
+fn end_upload() {
+    MANIFEST.with(|m| m.set(None));
+}
+
+/// Counts both upload paths' buffers against the manifest. Bytes are logical:
+/// a deduplicated chunk counts once per ID, as it will be consolidated.
+#[ic_cdk::query]
+fn upload_progress() -> UploadProgress {
+    let manifest = MANIFEST.with(|m| m.get());
+    let received_bytes = buffer_size() as u64 + parallel_total_bytes();
+    let received_chunks = SEQUENTIAL_CHUNKS.with(|c| c.get())
+        + BUFFER_MAP.with(|m| m.borrow().len() as u32);
+    let expected_bytes = manifest.map_or(0, |m| m.expected_bytes);
+
+    UploadProgress {
+        received_bytes,
+        expected_bytes,
+        received_chunks,
+        expected_chunks: manifest.map_or(0, |m| m.expected_chunks),
+        percent: if expected_bytes == 0 { 0. } else { received_bytes as f64 * 100. / expected_bytes as f64 },
+    }
+}
+
+// ═══════════════════════════════════════════════════════════════
+//  Sequential Upload
+// ═══════════════════════════════════════════════════════════════
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn append_chunk(chunk: Vec<u8>) -> Result<(), StorageError> {
+    append_chunk_sized(chunk).map(|_| ())
+}
+
+/// `append_chunk` returning the new buffer length, saving a `buffer_size` call per chunk.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn append_chunk_sized(chunk: Vec<u8>) -> Result<usize, StorageError> {
+    check_upload_quota(chunk.len(), 0)?;
+    let size = BUFFER.with(|b| {
+        let mut b = b.borrow_mut();
+        b.extend_from_slice(&chunk);
+        b.len()
+    });
+    SEQUENTIAL_CHUNKS.with(|c| c.set(c.get() + 1));
+    Ok(size)
+}
+
+/// `append_chunk_sized` that insists chunks arrive as `0, 1, 2, ...` since the
+/// buffer was last cleared: a gap fails with `MissingChunks`, a repeat with
+/// `UnexpectedChunks`, and neither touches the buffer.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn append_chunk_ordered(index: u32, chunk: Vec<u8>) -> Result<usize, StorageError> {
+    let expected = SEQUENTIAL_CHUNKS.with(|c| c.get());
+    if index > expected {
+        return Err(StorageError::MissingChunks((expected..index).collect()));
+    }
+    if index < expected {
+        return Err(StorageError::UnexpectedChunks(vec![index]));
+    }
+    append_chunk_sized(chunk)
+}
+
+#[ic_cdk::query]
+fn buffer_size() -> usize {
+    BUFFER.with(|b| b.borrow().len())
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn clear_buffer() {
+    BUFFER.with(|b| b.borrow_mut().clear());
+    SEQUENTIAL_CHUNKS.with(|c| c.set(0));
+    end_upload();
+}
+
+// ═══════════════════════════════════════════════════════════════
+//  Parallel Upload
+// ═══════════════════════════════════════════════════════════════
+
+/// Stores one chunk; when `crc32` is given the chunk is rejected on mismatch
+/// so the client can resend just that one.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn append_parallel_chunk(chunk_id: u32, chunk: Vec<u8>, crc32: Option<u32>) -> Result<(), StorageError> {
+    if let Some(expected) = crc32 {
+        let actual = crc32fast::hash(&chunk);
+        if actual != expected {
+            return Err(StorageError::ChecksumMismatch {
+                expected: format!("{:08x}", expected),
+                actual: format!("{:08x}", actual),
+            });
+        }
+    }
+
+    let blob = if DEDUP_CHUNKS.with(|d| d.get()) {
+        use sha2::{Digest, Sha256};
+
+        let digest: [u8; 32] = Sha256::digest(&chunk).into();
+        let shared = CHUNK_BLOBS.with(|b| b.borrow().get(&digest).and_then(Weak::upgrade));
+        match shared {
+            Some(blob) => blob,
+            None => {
+                check_upload_quota(chunk.len(), replaced_bytes(chunk_id))?;
+                let blob = Rc::new(chunk);
+                CHUNK_BLOBS.with(|b| b.borrow_mut().insert(digest, Rc::downgrade(&blob)));
+                blob
+            }
+        }
+    } else {
+        check_upload_quota(chunk.len(), replaced_bytes(chunk_id))?;
+        Rc::new(chunk)
+    };
+
+    BUFFER_MAP.with(|m| m.borrow_mut().insert(chunk_id, blob));
+    Ok(())
+}
+
+/// Heap freed by replacing `chunk_id`: nothing if its blob is shared.
+fn replaced_bytes(chunk_id: u32) -> usize {
+    BUFFER_MAP.with(|m| {
+        m.borrow().get(&chunk_id)
+            .filter(|c| Rc::strong_count(c) == 1)
+            .map_or(0, |c| c.len())
+    })
+}
+
+/// Content-addressed parallel uploads: an identical chunk (e.g. zero padding)
+/// is held once however many IDs carry it. Costs a SHA-256 per chunk.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn set_chunk_dedup(enabled: bool) {
+    DEDUP_CHUNKS.with(|d| d.set(enabled));
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn remove_parallel_chunk(chunk_id: u32) -> bool {
+    BUFFER_MAP.with(|m| m.borrow_mut().remove(&chunk_id).is_some())
+}
+
+#[ic_cdk::query]
+fn parallel_chunk_ids() -> Vec<u32> {
+    let mut ids: Vec<u32> = BUFFER_MAP.with(|m| m.borrow().keys().copied().collect());
+    ids.sort_unstable();
+    ids
+}
+
+#[ic_cdk::query]
+fn parallel_chunks_complete(expected_count: u32) -> bool {
+    parallel_chunks_missing(expected_count).is_empty()
+}
+
+/// IDs in `0..expected_count` that have not arrived yet, in ascending order.
+#[ic_cdk::query]
+fn parallel_chunks_missing(expected_count: u32) -> Vec<u32> {
+    BUFFER_MAP.with(|m| {
+        let m = m.borrow();
+        (0..expected_count).filter(|id| !m.contains_key(id)).collect()
+    })
+}
+
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn clear_parallel_chunks() {
+    BUFFER_MAP.with(|m| m.borrow_mut().clear());
+    CHUNK_BLOBS.with(|b| b.borrow_mut().clear());
+    end_upload();
+}
+
+/// Hex SHA-256 of the chunks in ID order, hashed chunk by chunk.
+fn parallel_chunks_sha256(chunks: &HashMap<u32, Rc<Vec<u8>>>) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut ids: Vec<&u32> = chunks.keys().collect();
+    ids.sort_unstable();
+
+    let mut hasher = Sha256::new();
+    for id in ids {
+        hasher.update(chunks[id].as_slice());
+    }
+    hex_digest(&hasher.finalize())
+}
+
+fn hex_digest(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Errors on a gap, extra chunk, manifest or digest mismatch, leaving the chunks in place.
+fn check_parallel_chunks(expected_count: u32, expected_sha256: Option<String>) -> Result<(), StorageError> {
+    let missing = parallel_chunks_missing(expected_count);
+    if !missing.is_empty() {
+        return Err(StorageError::MissingChunks(missing));
+    }
+    let extra: Vec<u32> = parallel_chunk_ids().into_iter().filter(|&id| id >= expected_count).collect();
+    if !extra.is_empty() {
+        return Err(StorageError::UnexpectedChunks(extra));
+    }
+
+    if let Some(manifest) = MANIFEST.with(|m| m.get()) {
+        if manifest.expected_chunks != expected_count {
+            return Err(StorageError::InvalidRequest(format!(
+                "Manifest announced {} chunks, consolidating {}",
+                manifest.expected_chunks, expected_count
+            )));
+        }
+        let actual = parallel_total_bytes();
+        if actual != manifest.expected_bytes {
+            return Err(StorageError::SizeMismatch { expected: manifest.expected_bytes, actual });
+        }
+    }
+
+    if let Some(expected) = expected_sha256 {
+        let actual = BUFFER_MAP.with(|m| parallel_chunks_sha256(&m.borrow()));
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(StorageError::ChecksumMismatch { expected, actual });
+        }
+    }
+
+    if BUFFER_MAP.with(|m| m.borrow().is_empty()) {
+        return Err(StorageError::EmptyBuffer);
+    }
+    Ok(())
+}
+
+/// Empties the parallel buffer, returning the chunks in ID order. They passed
+/// `check_parallel_chunks`, so the manifest has served its purpose.
+fn drain_parallel_chunks() -> Vec<Rc<Vec<u8>>> {
+    let chunks = BUFFER_MAP.with(|m| std::mem::take(&mut *m.borrow_mut()));
+    CHUNK_BLOBS.with(|b| b.borrow_mut().clear());
+    end_upload();
+    let mut sorted: Vec<(u32, Rc<Vec<u8>>)> = chunks.into_iter().collect();
+    sorted.sort_unstable_by_key(|(id, _)| *id);
+    sorted.into_iter().map(|(_, chunk)| chunk).collect()
+}
+
+/// Drains the parallel chunks in ID order into one contiguous vec.
+/// On a gap, manifest or digest mismatch the chunks are left in place.
+fn take_parallel_chunks(expected_count: u32, expected_sha256: Option<String>) -> Result<Vec<u8>, StorageError> {
+    check_parallel_chunks(expected_count, expected_sha256)?;
+    Ok(concat_chunks(drain_parallel_chunks()))
+}
+
+fn concat_chunks(chunks: Vec<Rc<Vec<u8>>>) -> Vec<u8> {
+    let total = chunks.iter().map(|c| c.len()).sum();
+    let mut data = Vec::with_capacity(total);
+    for chunk in chunks {
+        data.extend_from_slice(&chunk);
+    }
+    data
+}
+
+/// Moves the parallel chunks into the sequential buffer.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn consolidate_parallel_chunks(expected_count: u32, expected_sha256: Option<String>) -> Result<usize, StorageError> {
+    let data = take_parallel_chunks(expected_count, expected_sha256)?;
+    let size = data.len();
+    BUFFER.with(|b| *b.borrow_mut() = data);
+    SEQUENTIAL_CHUNKS.with(|c| c.set(expected_count));
+    Ok(size)
+}
+
+// ═══════════════════════════════════════════════════════════════
+//  Stable Storage
+// ═══════════════════════════════════════════════════════════════
+
+/// Encodes `data` per `compression`; callers check `ensure_supported` first.
+fn encode_stored(data: Vec<u8>, compression: Compression) -> Vec<u8> {
+    match compression {
+        Compression::None => data,
+        #[cfg(feature = "compression")]
+        Compression::Zstd => {
+            use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+            let mut out = ZSTD_HEADER.to_vec();
+            out.extend(compress_to_vec(data.as_slice(), CompressionLevel::Fastest));
+            out
+        }
+        #[cfg(not(feature = "compression"))]
+        Compression::Zstd => data,
+    }
+}
+
+/// Strips and inflates a zstd header if present; plain values pass through.
+fn decode_stored(data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+    if !data.starts_with(ZSTD_HEADER) {
+        return Ok(data);
+    }
+
+    #[cfg(feature = "compression")]
+    {
+        use std::io::Read;
+        let mut decoder = ruzstd::decoding::StreamingDecoder::new(&data[ZSTD_HEADER.len()..])
+            .map_err(|e| StorageError::Compression(format!("invalid zstd frame: {}", e)))?;
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out)
+            .map_err(|e| StorageError::Compression(e.to_string()))?;
+        Ok(out)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    Err(StorageError::Compression("value is compressed but the `compression` feature is disabled".to_string()))
+}
+
+/// IC time for sidecars; 0 off-chain, where there is no system API, so the
+/// storage paths can be unit-tested natively.
+fn now() -> u64 {
+    if cfg!(target_family = "wasm") {
+        ic_cdk::api::time()
+    } else {
+        0
+    }
+}
+
+fn meta_key(key: &str) -> String {
+    format!("{}__meta", key)
+}
+
+/// Sidecar for `data`; `sha256` is a digest the caller already verified, since
+/// hashing a model-sized value just for the record is too costly to do unasked.
+fn describe(data: &[u8], compression: Compression, sha256: Option<String>) -> KeyMetadata {
+    let format = ModelFormat::detect(data);
+    let (architecture, quantization) = match format {
+        Some(ModelFormat::Gguf) => qwen3::describe_gguf(data),
+        _ => (None, None),
+    };
+    KeyMetadata {
+        format,
+        architecture,
+        quantization,
+        compression,
+        uploaded_at: now(),
+        sha256: sha256.unwrap_or_default(),
+        size: data.len() as u64,
+        segment_sizes: None,
+        stored_size: None,
+    }
+}
+
+/// Splits a segment key `<base>.N` back to `<base>`; other keys are returned as is.
+fn blob_key(key: &str) -> &str {
+    match key.rsplit_once('.') {
+        Some((base, index)) if !base.is_empty() && index.parse::<u32>().is_ok() => base,
+        _ => key,
+    }
+}
+
+/// The sidecar written when `key` was last saved, if any.
+pub(crate) fn key_metadata(key: &str) -> Option<KeyMetadata> {
+    load_state(&meta_key(key))
+}
+
+/// Values larger than this are written as segments, each encoded on its own,
+/// so a `get_stable_data_chunk` page reads (and inflates) at most two.
+const SEGMENT_BYTES: usize = MAX_RESPONSE_BYTES;
+
+/// Writes bytes to `REGISTRIES` along with their sidecar metadata, returning
+/// the stored size. Anything previously stored under `key` is dropped first.
+fn write_stable(key: String, data: Vec<u8>, compression: Compression, sha256: Option<String>) -> usize {
+    let mut meta = describe(&data, compression, sha256);
+    if data.len() > SEGMENT_BYTES {
+        meta.segment_sizes = Some(data.chunks(SEGMENT_BYTES).map(|c| c.len() as u64).collect());
+    }
+    let size = REGISTRIES.with(|r| {
+        let mut r = r.borrow_mut();
+        remove_blob(&mut r, &key);
+        if data.len() <= SEGMENT_BYTES {
+            let data = encode_stored(data, compression);
+            let size = data.len();
+            r.insert(key.clone(), data);
+            return size;
+        }
+        let mut size = 0;
+        for (i, segment) in data.chunks(SEGMENT_BYTES).enumerate() {
+            let segment = encode_stored(segment.to_vec(), compression);
+            size += segment.len();
+            r.insert(segment_key(&key, i as u32), segment);
+        }
+        size
+    });
+    meta.stored_size = Some(size as u64);
+    save_state(&meta_key(&key), &meta);
+    size
+}
+
+const WASM_PAGE_BYTES: u64 = 64 * 1024;
+
+/// Pages per memory-manager bucket (the ic-stable-structures default), kept
+/// spare for the map's node overhead.
+const BUCKET_PAGES: u64 = 128;
+
+/// Grows stable memory ahead of writing `bytes`, so a save that can't fit fails
+/// with `QuotaExceeded` (required vs. current capacity) instead of trapping
+/// mid-insert. Space freed inside the map isn't counted, so overwrites are
+/// judged conservatively; pages grown here are used by the write that follows.
+/// Stable memory never shrinks, so callers run every other check first.
+fn reserve_stable(bytes: usize) -> Result<(), StorageError> {
+    let current = ic_cdk::stable::stable_size();
+    let used = crate::MEMORY_MANAGER.with(|m| m.borrow().get(crate::REGISTRIES_MEMORY_ID).size());
+    // One page for the memory manager's header
+    let required = 1 + used + (bytes as u64).div_ceil(WASM_PAGE_BYTES) + BUCKET_PAGES;
+    if required > current {
+        ic_cdk::stable::stable_grow(required - current).map_err(|_| StorageError::QuotaExceeded {
+            requested: required * WASM_PAGE_BYTES,
+            limit: current * WASM_PAGE_BYTES,
+        })?;
+    }
+    Ok(())
+}
+
+/// Errors if `key` is already stored (whole, as segments, or just its sidecar)
+/// and the caller did not opt into replacing it.
+fn check_overwrite(key: &str, overwrite: bool) -> Result<(), StorageError> {
+    let exists = has_stable_blob(key) || REGISTRIES.with(|r| r.borrow().contains_key(&meta_key(key)));
+    if !overwrite && exists {
+        return Err(StorageError::KeyExists(key.to_string()));
+    }
+    Ok(())
+}
+
+/// Persists the sequential buffer under `key` and clears it.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn save_to_stable(key: String, compression: Option<Compression>) -> Result<usize, StorageError> {
+    save_to_stable_checked(key, true, compression, None)
+}
+
+/// Like `save_to_stable`, but refuses to replace an existing key unless
+/// `overwrite` is set. With `expected_sha256` the buffer is verified first
+/// and the digest is recorded in the sidecar.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn save_to_stable_checked(
+    key: String,
+    overwrite: bool,
+    compression: Option<Compression>,
+    expected_sha256: Option<String>,
+) -> Result<usize, StorageError> {
+    let compression = compression.unwrap_or(Compression::None);
+    compression.ensure_supported()?;
+    check_overwrite(&key, overwrite)?;
+    if buffer_size() == 0 {
+        return Err(StorageError::EmptyBuffer);
+    }
+    // Checked before taking the buffer, so the upload survives a failure
+    let verified = match expected_sha256 {
+        Some(expected) => {
+            use sha2::{Digest, Sha256};
+
+            let actual = BUFFER.with(|b| hex_digest(&Sha256::digest(b.borrow().as_slice())));
+            if !actual.eq_ignore_ascii_case(expected.trim()) {
+                return Err(StorageError::ChecksumMismatch { expected, actual });
+            }
+            Some(actual)
+        }
+        None => None,
+    };
+    // Last, since grown pages can't be given back if a check fails
+    reserve_stable(buffer_size())?;
+    let data = BUFFER.with(|b| std::mem::take(&mut *b.borrow_mut()));
+    SEQUENTIAL_CHUNKS.with(|c| c.set(0));
+    end_upload();
+    Ok(write_stable(key, data, compression, verified))
+}
+
+/// Persists the parallel chunks under `key` without going through the sequential buffer.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn save_parallel_to_stable(
+    key: String,
+    expected_count: u32,
+    expected_sha256: Option<String>,
+    overwrite: bool,
+    compression: Option<Compression>,
+) -> Result<usize, StorageError> {
+    let compression = compression.unwrap_or(Compression::None);
+    compression.ensure_supported()?;
+    check_overwrite(&key, overwrite)?;
+    // The verified digest is recorded as is rather than hashing again
+    let verified = expected_sha256.as_ref().map(|s| s.trim().to_ascii_lowercase());
+    check_parallel_chunks(expected_count, expected_sha256)?;
+    // Last, since grown pages can't be given back if a check fails
+    reserve_stable(parallel_total_bytes() as usize)?;
+    let data = concat_chunks(drain_parallel_chunks());
+    Ok(write_stable(key, data, compression, verified))
+}
+
+/// Like `save_parallel_to_stable`, but writes each chunk as a segment of `key`
+/// instead of concatenating them first, so the heap never holds a second copy
+/// of the file. Segments can't be compressed; the sidecar records no
+/// architecture since the GGUF header may span chunks.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn save_parallel_to_stable_segments(
+    key: String,
+    expected_count: u32,
+    expected_sha256: Option<String>,
+    overwrite: bool,
+) -> Result<usize, StorageError> {
+    check_overwrite(&key, overwrite)?;
+    // Only a verified digest is recorded; none is computed unasked
+    let verified = expected_sha256.as_ref().map(|s| s.trim().to_ascii_lowercase());
+    check_parallel_chunks(expected_count, expected_sha256)?;
+    reserve_stable(parallel_total_bytes() as usize)?;
+
+    let sha256 = verified.unwrap_or_default();
+    let chunks = drain_parallel_chunks();
+    let format = chunks.first().and_then(|c| ModelFormat::detect(c));
+
+    let segment_sizes: Vec<u64> = chunks.iter().map(|c| c.len() as u64).collect();
+    let size = REGISTRIES.with(|r| {
+        let mut r = r.borrow_mut();
+        remove_blob(&mut r, &key);
+        let mut size = 0;
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            size += chunk.len();
+            r.insert(segment_key(&key, i as u32), Rc::unwrap_or_clone(chunk));
+        }
+        size
+    });
+
+    save_state(&meta_key(&key), &KeyMetadata {
+        format,
+        architecture: None,
+        quantization: None,
+        compression: Compression::None,
+        uploaded_at: now(),
+        sha256,
+        size: size as u64,
+        segment_sizes: Some(segment_sizes),
+        stored_size: Some(size as u64),
+    });
+    Ok(size)
+}
+
+/// Splits the sequential buffer into `shard_bytes`-sized segments of
+/// `base_key` (`<base_key>.0`, `<base_key>.1`, ...) and clears it, returning
+/// the keys written. Segments are read back concatenated wherever the key is
+/// loaded, so the model can be set up from `base_key` as usual. Refuses to
+/// replace an existing value unless `overwrite` is set.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn save_to_stable_sharded(base_key: String, shard_bytes: usize, overwrite: bool) -> Result<Vec<String>, StorageError> {
+    if shard_bytes == 0 {
+        return Err(StorageError::InvalidRequest("shard_bytes must be positive".to_string()));
+    }
+    check_overwrite(&base_key, overwrite)?;
+    if buffer_size() == 0 {
+        return Err(StorageError::EmptyBuffer);
+    }
+    // Checked before taking the buffer, so the upload survives a failure
+    reserve_stable(buffer_size())?;
+    let data = BUFFER.with(|b| std::mem::take(&mut *b.borrow_mut()));
+    SEQUENTIAL_CHUNKS.with(|c| c.set(0));
+    end_upload();
+
+    let mut meta = describe(&data, Compression::None, None);
+    meta.segment_sizes = Some(data.chunks(shard_bytes).map(|shard| shard.len() as u64).collect());
+    meta.stored_size = Some(data.len() as u64);
+    save_state(&meta_key(&base_key), &meta);
+    let keys = REGISTRIES.with(|r| {
+        let mut r = r.borrow_mut();
+        remove_blob(&mut r, &base_key);
+        data.chunks(shard_bytes).enumerate().map(|(i, shard)| {
+            let key = segment_key(&base_key, i as u32);
+            r.insert(key.clone(), shard.to_vec());
+            key
+        }).collect()
+    });
+    Ok(keys)
+}
+
+/// Candid-encodes small canister state under a reserved key.
+pub(crate) fn save_state<T: CandidType>(key: &str, value: &T) {
+    match candid::encode_one(value) {
+        Ok(bytes) => REGISTRIES.with(|r| ic_dev_kit_rs::storage::save_bytes(r, key, bytes)),
+        Err(e) => ic_dev_kit_rs::telemetry::log_info(&format!("Failed to encode '{}': {}", key, e)),
+    }
+}
+
+/// Decodes state written by `save_state`, or `None` if absent or unreadable.
+pub(crate) fn load_state<T: CandidType + DeserializeOwned>(key: &str) -> Option<T> {
+    let bytes = REGISTRIES.with(|r| r.borrow().get(&key.to_string()))?;
+    candid::decode_one(&bytes).ok()
+}
+
+// Large blobs can be stored as numbered segments `<key>.0`, `<key>.1`, ...
+// so an upload never has to hold the whole file in the heap.
+
+fn segment_key(key: &str, index: u32) -> String {
+    format!("{}.{}", key, index)
+}
+
+/// Number of consecutive segments stored for `key`.
+fn segment_count(key: &str) -> u32 {
+    REGISTRIES.with(|r| {
+        let r = r.borrow();
+        (0..).take_while(|&i| r.contains_key(&segment_key(key, i))).count() as u32
+    })
+}
+
+/// Removes `key` whether stored whole or as segments, so layouts never mix,
+/// returning the stored bytes freed. The sidecar is left to the caller.
+fn remove_blob(r: &mut StableBTreeMap<String, Vec<u8>, Memory>, key: &str) -> usize {
+    let mut freed = r.remove(&key.to_string()).map_or(0, |data| data.len());
+    for i in 0.. {
+        let Some(segment) = r.remove(&segment_key(key, i)) else { break };
+        freed += segment.len();
+    }
+    freed
+}
+
+/// Every stored key, with a segmented value listed once under its base key.
+fn stored_blobs() -> BTreeSet<String> {
+    REGISTRIES.with(|r| {
+        let r = r.borrow();
+        r.keys()
+            .map(|key| {
+                // Fold `<base>.N` into `<base>` when it is a segmented value
+                let base = blob_key(&key);
+                let segmented = base != key
+                    && !r.contains_key(&base.to_string())
+                    && r.contains_key(&segment_key(base, 0));
+                if segmented { base.to_string() } else { key }
+            })
+            .collect()
+    })
+}
+
+/// Size of the value at `key`, whole or segmented. Taken from the sidecar when
+/// there is one (the uncompressed size), so the value isn't copied out; only
+/// values without one, such as canister state, are read to measure them.
+fn blob_size(key: &str) -> Option<u64> {
+    if let Some(meta) = key_metadata(key).filter(|_| has_stable_blob(key)) {
+        return Some(meta.size);
+    }
+    measured_size(key)
+}
+
+/// Bytes `key` occupies as stored (compressed, all segments): from the sidecar
+/// when it records them, otherwise measured.
+fn stored_size(key: &str) -> Option<u64> {
+    if let Some(size) = key_metadata(key).and_then(|m| m.stored_size).filter(|_| has_stable_blob(key)) {
+        return Some(size);
+    }
+    measured_size(key)
+}
+
+/// Reads the value at `key` (or its segments) just to measure it.
+fn measured_size(key: &str) -> Option<u64> {
+    REGISTRIES.with(|r| {
+        let r = r.borrow();
+        if let Some(data) = r.get(&key.to_string()) {
+            return Some(data.len() as u64);
+        }
+        let sizes: Vec<u64> = (0..)
+            .map_while(|i| r.get(&segment_key(key, i)).map(|segment| segment.len() as u64))
+            .collect();
+        (!sizes.is_empty()).then(|| sizes.iter().sum())
+    })
+}
+
+/// Whether `key` is stored either whole or as segments.
+pub(crate) fn has_stable_blob(key: &str) -> bool {
+    REGISTRIES.with(|r| r.borrow().contains_key(&key.to_string())) || segment_count(key) > 0
+}
+
+/// Returns the entry at `key` (or its segments concatenated in order),
+/// decompressed if it was saved with `Compression::Zstd`.
+pub(crate) fn read_stable_blob(key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+    match REGISTRIES.with(|r| r.borrow().get(&key.to_string())) {
+        Some(data) => decode_stored(data).map(Some),
+        None => read_segments(key),
+    }
+}
+
+/// Segments are encoded one by one, so each is inflated before joining.
+fn read_segments(key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+    let count = segment_count(key);
+    if count == 0 {
+        return Ok(None);
+    }
+    REGISTRIES.with(|r| {
+        let r = r.borrow();
+        let mut data = Vec::new();
+        for i in 0..count {
+            let Some(segment) = r.get(&segment_key(key, i)) else { break };
+            data.extend(decode_stored(segment)?);
+        }
+        Ok(Some(data))
+    })
+}
+
+/// Appends `chunk` as the next segment of `key` directly in stable storage,
+/// bypassing the heap buffer. Returns the number of segments stored so far.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn append_chunk_to_stable(key: String, chunk: Vec<u8>) -> Result<u32, StorageError> {
+    if chunk.is_empty() {
+        return Err(StorageError::EmptyBuffer);
+    }
+    if REGISTRIES.with(|r| r.borrow().contains_key(&key)) {
+        return Err(StorageError::InvalidRequest(format!("Key '{}' already holds a single entry", key)));
+    }
+    reserve_stable(chunk.len())?;
+
+    let index = segment_count(&key);
+    // Keep a running sidecar so sizes never need the segments read back
+    let meta = if index == 0 {
+        Some(KeyMetadata {
+            format: ModelFormat::detect(&chunk),
+            architecture: None,
+            quantization: None,
+            compression: Compression::None,
+            uploaded_at: 0,
+            sha256: String::new(),
+            size: 0,
+            segment_sizes: Some(Vec::new()),
+            stored_size: Some(0),
+        })
+    } else {
+        key_metadata(&key)
+    };
+    if let Some(mut meta) = meta {
+        meta.uploaded_at = now();
+        meta.sha256.clear();
+        meta.size += chunk.len() as u64;
+        meta.stored_size = meta.stored_size.map(|size| size + chunk.len() as u64);
+        if let Some(sizes) = &mut meta.segment_sizes {
+            sizes.push(chunk.len() as u64);
+        }
+        save_state(&meta_key(&key), &meta);
+    }
+    REGISTRIES.with(|r| r.borrow_mut().insert(segment_key(&key, index), chunk));
+    Ok(index + 1)
+}
+
+/// Copies a stable entry back into the sequential buffer, decompressing if
+/// needed. The value replaces the buffer, so it counts against the upload
+/// quota in place of what the buffer held; that is checked before reading.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn load_from_stable(key: String) -> Result<usize, StorageError> {
+    let incoming = blob_size(&key).ok_or_else(|| StorageError::KeyNotFound(key.clone()))?;
+    check_upload_quota(incoming as usize, buffer_size())?;
+    let data = read_stable_blob(&key)?
+        .ok_or(StorageError::KeyNotFound(key))?;
+    let size = data.len();
+    BUFFER.with(|b| *b.borrow_mut() = data);
+    SEQUENTIAL_CHUNKS.with(|c| c.set(0));
+    Ok(size)
+}
+
+/// Raw bytes of a value stored whole; larger ones are segmented, so page
+/// through those with `get_stable_data_chunk`.
+#[ic_cdk::query]
+fn get_stable_data(key: String) -> Option<Vec<u8>> {
+    REGISTRIES.with(|r| r.borrow().get(&key))
+}
+
+/// Removes a stable entry, whole or segmented, with its sidecar, returning the
+/// number of bytes freed. Nothing is touched when `key` isn't stored.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn delete_stable_key(key: String) -> Result<usize, StorageError> {
+    if !has_stable_blob(&key) {
+        return Err(StorageError::KeyNotFound(key));
+    }
+    Ok(REGISTRIES.with(|r| {
+        let mut r = r.borrow_mut();
+        r.remove(&meta_key(&key));
+        remove_blob(&mut r, &key)
+    }))
+}
+
+/// Confirmation string `clear_all_stable` requires.
+const CLEAR_ALL_CONFIRMATION: &str = "DELETE_ALL";
+
+/// Removes every uploaded entry and unloads the model, returning how many keys
+/// were removed. Canister state under `__`-prefixed keys (auth, settings) is
+/// kept. Refused while the model is pinned.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn clear_all_stable(confirm: String) -> Result<usize, StorageError> {
+    if confirm != CLEAR_ALL_CONFIRMATION {
+        return Err(StorageError::InvalidRequest(format!("Pass \"{}\" to confirm", CLEAR_ALL_CONFIRMATION)));
+    }
+
+    crate::models::check_evictable().map_err(StorageError::InvalidRequest)?;
+    crate::MODEL_SERVER.with(|server| server.unload());
+    crate::models::set_loaded(None);
+    let removed = REGISTRIES.with(|r| {
+        let mut r = r.borrow_mut();
+        let keys: Vec<String> = r.keys().filter(|key| !key.starts_with("__")).collect();
+        for key in &keys {
+            r.remove(key);
+        }
+        keys.len()
+    });
+    ic_dev_kit_rs::telemetry::log_info(&format!("Cleared {} stable entries", removed));
+    Ok(removed)
+}
+
+/// Moves `from` (whole or segmented, plus its sidecar) onto `to`, replacing
+/// whatever `to` held, and returns the bytes moved.
+pub(crate) fn promote(from: &str, to: &str) -> Result<usize, StorageError> {
+    if from == to {
+        return Err(StorageError::InvalidRequest("Source and destination keys are the same".to_string()));
+    }
+    if !has_stable_blob(from) {
+        return Err(StorageError::KeyNotFound(from.to_string()));
+    }
+
+    REGISTRIES.with(|r| {
+        let mut r = r.borrow_mut();
+        remove_blob(&mut r, to);
+        match r.remove(&meta_key(from)) {
+            Some(meta) => r.insert(meta_key(to), meta),
+            None => r.remove(&meta_key(to)),
+        };
+
+        if let Some(data) = r.remove(&from.to_string()) {
+            let size = data.len();
+            r.insert(to.to_string(), data);
+            return Ok(size);
+        }
+        let mut size = 0;
+        for i in 0.. {
+            let Some(segment) = r.remove(&segment_key(from, i)) else { break };
+            size += segment.len();
+            r.insert(segment_key(to, i), segment);
+        }
+        Ok(size)
+    })
+}
+
+/// Moves a staged entry onto `to` (replacing it) and drops the staging key.
+/// Load-test the staged weights with `setup_model_from` before promoting.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn promote_stable_key(from: String, to: String) -> Result<usize, StorageError> {
+    promote(&from, &to)
+}
+
+/// Duplicates `from` (whole or segmented, plus its sidecar) onto `to`, e.g. as
+/// a rollback point before promoting new weights. One entry or segment is in
+/// the heap at a time, so a whole-entry copy needs memory for that value once.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn copy_stable_key(from: String, to: String, overwrite: bool) -> Result<usize, StorageError> {
+    if from == to {
+        return Err(StorageError::InvalidRequest("Source and destination keys are the same".to_string()));
+    }
+    if !has_stable_blob(&from) {
+        return Err(StorageError::KeyNotFound(from));
+    }
+    check_overwrite(&to, overwrite)?;
+    reserve_stable(blob_size(&from).unwrap_or(0) as usize)?;
+
+    REGISTRIES.with(|r| {
+        let mut r = r.borrow_mut();
+        remove_blob(&mut r, &to);
+        match r.get(&meta_key(&from)) {
+            Some(meta) => r.insert(meta_key(&to), meta),
+            None => r.remove(&meta_key(&to)),
+        };
+
+        if let Some(data) = r.get(&from) {
+            let size = data.len();
+            r.insert(to, data);
+            return Ok(size);
+        }
+        let mut size = 0;
+        for i in 0.. {
+            let Some(segment) = r.get(&segment_key(&from, i)) else { break };
+            size += segment.len();
+            r.insert(segment_key(&to, i), segment);
+        }
+        Ok(size)
+    })
+}
+
+/// Pages through a stored value, whole or segmented; `len` is clamped to
+/// `MAX_RESPONSE_BYTES`. Only values up to `SEGMENT_BYTES` are stored whole,
+/// and a compressed one is inflated in full since a zstd frame can't be
+/// sliced; larger values are read segment by segment.
+#[ic_cdk::query]
+fn get_stable_data_chunk(key: String, offset: usize, len: usize) -> Result<Vec<u8>, StorageError> {
+    let len = len.min(MAX_RESPONSE_BYTES);
+    let Some(data) = REGISTRIES.with(|r| r.borrow().get(&key)) else {
+        return read_segment_range(&key, offset, len);
+    };
+
+    let data = decode_stored(data)?;
+    if offset > data.len() {
+        return Err(StorageError::OutOfRange { offset: offset as u64, size: data.len() as u64 });
+    }
+    let end = offset.saturating_add(len).min(data.len());
+    Ok(data[offset..end].to_vec())
+}
+
+/// Bytes `offset..offset + len` of the segmented value `key`. Segments the
+/// sidecar's (uncompressed) `segment_sizes` place before the range are
+/// skipped unread, so only the overlapping ones are copied out and inflated.
+fn read_segment_range(key: &str, offset: usize, len: usize) -> Result<Vec<u8>, StorageError> {
+    let sizes = key_metadata(key).and_then(|m| m.segment_sizes).unwrap_or_default();
+    let end = offset.saturating_add(len);
+    REGISTRIES.with(|r| {
+        let r = r.borrow();
+        if !r.contains_key(&segment_key(key, 0)) {
+            return Err(StorageError::KeyNotFound(key.to_string()));
+        }
+
+        let mut out = Vec::new();
+        // Offset of segment `i` within the value
+        let mut start = 0;
+        for i in 0.. {
+            if start >= end {
+                break;
+            }
+            if let Some(&size) = sizes.get(i as usize) {
+                if start + size as usize <= offset {
+                    start += size as usize;
+                    continue;
+                }
+            }
+            let Some(segment) = r.get(&segment_key(key, i)) else { break };
+            let segment = decode_stored(segment)?;
+            let segment_end = start + segment.len();
+            if segment_end > offset {
+                out.extend_from_slice(&segment[offset.max(start) - start..end.min(segment_end) - start]);
+            }
+            start = segment_end;
+        }
+
+        if offset > start {
+            return Err(StorageError::OutOfRange { offset: offset as u64, size: start as u64 });
+        }
+        Ok(out)
+    })
+}
+
+/// Rehashes one page of values against the SHA-256 in their sidecars, in key
+/// order after `start_after`: at most `limit` (at least 1) are hashed, so a
+/// large model can be checked across several calls. Values without a
+/// recorded digest are listed in `skipped` rather than hashed. Runs as an
+/// update to get the larger instruction limit.
+#[ic_cdk::update(guard = "ic_dev_kit_rs::auth::is_authorized")]
+fn verify_stable_integrity(start_after: Option<String>, limit: u32) -> IntegrityReport {
+    use sha2::{Digest, Sha256};
+
+    let limit = limit.max(1) as usize;
+    let mut report = IntegrityReport { results: Vec::new(), skipped: Vec::new(), next: None };
+    let blobs = stored_blobs().into_iter()
+        .filter(|key| !key.starts_with("__") && !key.ends_with("__meta"))
+        .filter(|key| start_after.as_ref().map_or(true, |start| key > start));
+
+    let mut last = None;
+    for key in blobs {
+        if report.results.len() == limit {
+            report.next = last;
+            break;
+        }
+        last = Some(key.clone());
+        let Some(meta) = key_metadata(&key).filter(|m| !m.sha256.is_empty()) else {
+            report.skipped.push(key);
+            continue;
+        };
+        let actual = read_stable_blob(&key).ok().flatten()
+            .map(|data| hex_digest(&Sha256::digest(&data)));
+        report.results.push(IntegrityResult {
+            ok: actual.as_deref() == Some(meta.sha256.as_str()),
+            key,
+            expected: meta.sha256,
+            actual,
+        });
+    }
+    report
+}
+
+#[ic_cdk::query]
+fn stable_key_metadata(key: String) -> Option<KeyMetadata> {
+    key_metadata(&key)
+}
+
+#[ic_cdk::query]
+fn list_stable_keys() -> Vec<String> {
+    REGISTRIES.with(|r| r.borrow().keys().collect())
+}
+
+/// Size of `key` whole or segmented; see `blob_size`.
+#[ic_cdk::query]
+fn stable_key_size(key: String) -> Option<usize> {
+    blob_size(&key).map(|size| size as usize)
+}
+
+/// All key sizes in one round trip, instead of `stable_key_size` per key.
+#[ic_cdk::query]
+fn stable_storage_summary() -> StableSummary {
+    let entries: Vec<(String, usize)> = stored_blobs().into_iter()
+        .map(|key| {
+            let size = blob_size(&key).unwrap_or(0) as usize;
+            (key, size)
+        })
+        .collect();
+
+    StableSummary {
+        total_bytes: entries.iter().map(|(_, size)| *size as u64).sum(),
+        entries,
+        stable_pages: ic_cdk::stable::stable_size(),
+    }
+}
+
+#[ic_cdk::query]
+fn memory_stats() -> MemoryStats {
+    let blobs = stored_blobs();
+    let stable_total_bytes = blobs.iter().filter_map(|key| stored_size(key)).sum();
+    let stable_logical_bytes = blobs.iter().filter_map(|key| blob_size(key)).sum();
+
+    MemoryStats {
+        heap_buffer_bytes: BUFFER.with(|b| b.borrow().len() as u64),
+        parallel_buffer_bytes: parallel_heap_bytes(),
+        stable_total_bytes,
+        stable_logical_bytes,
+        model_loaded: crate::MODEL_SERVER.with(|server| server.is_loaded()),
+        wasm_pages: ic_cdk::stable::stable_size(),
+    }
+}
+
+#[ic_cdk::query]
+fn storage_status() -> String {
+    let buffer = buffer_size();
+    let (chunks, chunk_bytes) = BUFFER_MAP.with(|m| {
+        let m = m.borrow();
+        (m.len(), m.values().map(|c| c.len()).sum::<usize>())
+    });
+
+    let mut status = format!(
+        "Buffer: {} bytes\nParallel chunks: {} ({} bytes)\nStable entries:\n",
+        buffer, chunks, chunk_bytes
+    );
+    for key in stored_blobs() {
+        let size = blob_size(&key).unwrap_or(0);
+        status.push_str(&format!("  {}: {} bytes\n", key, size));
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload_parallel(chunks: &[&[u8]]) {
+        for (id, chunk) in chunks.iter().enumerate() {
+            append_parallel_chunk(id as u32, chunk.to_vec(), None).unwrap();
+        }
+    }
+
+    #[test]
+    fn manifest_covers_one_upload() {
+        begin_upload(6, 2);
+        upload_parallel(&[b"abc", b"def"]);
+        assert_eq!(consolidate_parallel_chunks(2, None), Ok(6));
+        assert_eq!(upload_progress().expected_bytes, 0);
+        clear_buffer();
+
+        // No begin_upload this time, so nothing is cross-checked
+        upload_parallel(&[b"gh", b"ij", b"kl"]);
+        assert_eq!(consolidate_parallel_chunks(3, None), Ok(6));
+        assert_eq!(BUFFER.with(|b| b.borrow().clone()), b"ghijkl");
+        clear_buffer();
+    }
+
+    #[test]
+    fn clearing_chunks_drops_the_manifest() {
+        begin_upload(100, 4);
+        upload_parallel(&[b"abc"]);
+        clear_parallel_chunks();
+
+        upload_parallel(&[b"xyz"]);
+        assert_eq!(consolidate_parallel_chunks(1, None), Ok(3));
+        clear_buffer();
+    }
+
+    #[test]
+    fn manifest_mismatch_keeps_the_chunks() {
+        begin_upload(5, 2);
+        upload_parallel(&[b"abc", b"def"]);
+        assert_eq!(
+            consolidate_parallel_chunks(2, None),
+            Err(StorageError::SizeMismatch { expected: 5, actual: 6 })
+        );
+        assert_eq!(parallel_chunk_ids(), vec![0, 1]);
+        clear_parallel_chunks();
+    }
+
+    #[test]
+    fn large_values_page_across_segments() {
+        let data: Vec<u8> = (0..SEGMENT_BYTES * 2 + 10).map(|i| (i % 251) as u8).collect();
+        write_stable("big".to_string(), data.clone(), Compression::None, None);
+        assert_eq!(segment_count("big"), 3);
+        assert!(!REGISTRIES.with(|r| r.borrow().contains_key(&"big".to_string())));
+
+        let offset = SEGMENT_BYTES - 5;
+        assert_eq!(get_stable_data_chunk("big".to_string(), offset, 10), Ok(data[offset..offset + 10].to_vec()));
+        assert_eq!(read_stable_blob("big"), Ok(Some(data.clone())));
+        assert_eq!(blob_size("big"), Some(data.len() as u64));
+        assert_eq!(
+            get_stable_data_chunk("big".to_string(), data.len() + 1, 1),
+            Err(StorageError::OutOfRange { offset: data.len() as u64 + 1, size: data.len() as u64 })
+        );
+    }
+
+    #[test]
+    fn stored_size_counts_values_without_a_sidecar() {
+        write_stable("weights".to_string(), vec![1; 300], Compression::None, None);
+        REGISTRIES.with(|r| r.borrow_mut().insert("raw".to_string(), vec![0; 42]));
+        assert_eq!(stored_size("weights"), Some(300));
+        assert_eq!(key_metadata("weights").and_then(|m| m.stored_size), Some(300));
+        assert_eq!(stored_size("raw"), Some(42));
+        assert_eq!(stored_size("missing"), None);
+    }
+
+    #[test]
+    fn overwriting_replaces_the_old_layout() {
+        let big: Vec<u8> = vec![7; SEGMENT_BYTES + 1];
+        write_stable("k".to_string(), big, Compression::None, None);
+        assert_eq!(segment_count("k"), 2);
+        assert_eq!(check_overwrite("k", false), Err(StorageError::KeyExists("k".to_string())));
+        assert_eq!(check_overwrite("k", true), Ok(()));
+
+        write_stable("k".to_string(), b"small".to_vec(), Compression::None, None);
+        assert_eq!(segment_count("k"), 0);
+        assert_eq!(read_stable_blob("k"), Ok(Some(b"small".to_vec())));
+        assert_eq!(key_metadata("k").map(|m| (m.size, m.segment_sizes)), Some((5, None)));
+
+        // A sidecar left without its value still blocks a plain save
+        REGISTRIES.with(|r| r.borrow_mut().remove(&"k".to_string()));
+        assert_eq!(check_overwrite("k", false), Err(StorageError::KeyExists("k".to_string())));
+    }
+
+    #[test]
+    fn delete_removes_segments_and_sidecar() {
+        let data: Vec<u8> = vec![1; SEGMENT_BYTES + 100];
+        write_stable("seg".to_string(), data.clone(), Compression::None, None);
+        assert_eq!(delete_stable_key("seg".to_string()), Ok(data.len()));
+        assert!(!has_stable_blob("seg"));
+        assert!(key_metadata("seg").is_none());
+        assert_eq!(delete_stable_key("seg".to_string()), Err(StorageError::KeyNotFound("seg".to_string())));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_values_round_trip() {
+        let data: Vec<u8> = b"qwen3 ".iter().copied().cycle().take(SEGMENT_BYTES * 2 + 3).collect();
+        let stored = write_stable("z".to_string(), data.clone(), Compression::Zstd, None);
+        assert!(stored < data.len());
+        assert_eq!(stored_size("z"), Some(stored as u64));
+        assert_eq!(blob_size("z"), Some(data.len() as u64));
+        assert_eq!(read_stable_blob("z"), Ok(Some(data.clone())));
+
+        let offset = SEGMENT_BYTES * 2 - 4;
+        assert_eq!(get_stable_data_chunk("z".to_string(), offset, 7), Ok(data[offset..offset + 7].to_vec()));
+
+        write_stable("small".to_string(), b"tiny".to_vec(), Compression::Zstd, None);
+        assert!(REGISTRIES.with(|r| r.borrow().get(&"small".to_string())).unwrap().starts_with(ZSTD_HEADER));
+        assert_eq!(read_stable_blob("small"), Ok(Some(b"tiny".to_vec())));
+    }
+
+    #[test]
+    fn plain_values_pass_through_decoding() {
+        assert_eq!(decode_stored(b"plain".to_vec()), Ok(b"plain".to_vec()));
+        assert_eq!(encode_stored(b"plain".to_vec(), Compression::None), b"plain");
+    }
+}
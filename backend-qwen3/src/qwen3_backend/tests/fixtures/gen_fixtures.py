@@ -0,0 +1,154 @@
+#!/usr/bin/env python3
+"""Regenerates the benchmark fixtures next to this script.
+
+model.gguf is a tiny random-weight Qwen3 (F32, GGUF v3); tokenizer.json is a
+WordLevel vocab covering the bench prompt. Output is deterministic, so the
+committed files only change when this script does.
+
+    python3 tests/fixtures/gen_fixtures.py
+"""
+
+import json
+import os
+import random
+import struct
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+WORDS = ["Once", "upon", "a", "time", "in", "land", "far", "away"]
+SPECIAL = ["<|endoftext|>", "<|im_end|>"]
+VOCAB = ["[UNK]"] + WORDS + SPECIAL
+
+HIDDEN = 16
+HEADS = 2
+KV_HEADS = 1
+HEAD_DIM = 8
+FFN = 32
+BLOCKS = 2
+CONTEXT = 128
+
+ALIGNMENT = 32
+GGUF_UINT32 = 4
+GGUF_FLOAT32 = 6
+GGUF_STRING = 8
+GGML_F32 = 0
+
+
+def gguf_string(s):
+    data = s.encode("utf-8")
+    return struct.pack("<Q", len(data)) + data
+
+
+def kv(key, kind, value):
+    out = gguf_string(key) + struct.pack("<I", kind)
+    if kind == GGUF_UINT32:
+        return out + struct.pack("<I", value)
+    if kind == GGUF_FLOAT32:
+        return out + struct.pack("<f", value)
+    return out + gguf_string(value)
+
+
+def tensors(rng):
+    """(name, shape, values) with shapes as candle sees them, outermost first."""
+
+    def rand(*shape):
+        n = 1
+        for d in shape:
+            n *= d
+        return shape, [rng.gauss(0.0, 0.3) for _ in range(n)]
+
+    def ones(n):
+        return (n,), [1.0] * n
+
+    out = [
+        ("token_embd.weight", *rand(len(VOCAB), HIDDEN)),
+        ("output_norm.weight", *ones(HIDDEN)),
+        ("output.weight", *rand(len(VOCAB), HIDDEN)),
+    ]
+    for i in range(BLOCKS):
+        blk = "blk.%d." % i
+        out += [
+            (blk + "attn_norm.weight", *ones(HIDDEN)),
+            (blk + "attn_q.weight", *rand(HEADS * HEAD_DIM, HIDDEN)),
+            (blk + "attn_k.weight", *rand(KV_HEADS * HEAD_DIM, HIDDEN)),
+            (blk + "attn_v.weight", *rand(KV_HEADS * HEAD_DIM, HIDDEN)),
+            (blk + "attn_output.weight", *rand(HIDDEN, HEADS * HEAD_DIM)),
+            (blk + "attn_q_norm.weight", *ones(HEAD_DIM)),
+            (blk + "attn_k_norm.weight", *ones(HEAD_DIM)),
+            (blk + "ffn_norm.weight", *ones(HIDDEN)),
+            (blk + "ffn_gate.weight", *rand(FFN, HIDDEN)),
+            (blk + "ffn_up.weight", *rand(FFN, HIDDEN)),
+            (blk + "ffn_down.weight", *rand(HIDDEN, FFN)),
+        ]
+    return out
+
+
+def write_gguf(path):
+    metadata = [
+        kv("general.architecture", GGUF_STRING, "qwen3"),
+        kv("general.alignment", GGUF_UINT32, ALIGNMENT),
+        kv("qwen3.attention.head_count", GGUF_UINT32, HEADS),
+        kv("qwen3.attention.head_count_kv", GGUF_UINT32, KV_HEADS),
+        kv("qwen3.attention.key_length", GGUF_UINT32, HEAD_DIM),
+        kv("qwen3.attention.value_length", GGUF_UINT32, HEAD_DIM),
+        kv("qwen3.block_count", GGUF_UINT32, BLOCKS),
+        kv("qwen3.embedding_length", GGUF_UINT32, HIDDEN),
+        kv("qwen3.feed_forward_length", GGUF_UINT32, FFN),
+        kv("qwen3.context_length", GGUF_UINT32, CONTEXT),
+        kv("qwen3.attention.layer_norm_rms_epsilon", GGUF_FLOAT32, 1e-6),
+        kv("qwen3.rope.freq_base", GGUF_FLOAT32, 1e6),
+    ]
+    rng = random.Random(550)
+    items = tensors(rng)
+
+    infos = b""
+    data = b""
+    for name, shape, values in items:
+        # Offsets are relative to the (aligned) start of the data section
+        data += b"\0" * (-len(data) % ALIGNMENT)
+        # GGUF lists dimensions innermost first
+        infos += gguf_string(name) + struct.pack("<I", len(shape))
+        infos += b"".join(struct.pack("<Q", d) for d in reversed(shape))
+        infos += struct.pack("<IQ", GGML_F32, len(data))
+        data += struct.pack("<%df" % len(values), *values)
+
+    header = b"GGUF" + struct.pack("<IQQ", 3, len(items), len(metadata))
+    header += b"".join(metadata) + infos
+    header += b"\0" * (-len(header) % ALIGNMENT)
+    with open(path, "wb") as f:
+        f.write(header + data)
+
+
+def write_tokenizer(path):
+    vocab = {token: i for i, token in enumerate(VOCAB)}
+    added = [
+        {
+            "id": vocab[token],
+            "content": token,
+            "single_word": False,
+            "lstrip": False,
+            "rstrip": False,
+            "normalized": False,
+            "special": True,
+        }
+        for token in SPECIAL
+    ]
+    tokenizer = {
+        "version": "1.0",
+        "truncation": None,
+        "padding": None,
+        "added_tokens": added,
+        "normalizer": None,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": None,
+        "decoder": None,
+        "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]"},
+    }
+    with open(path, "w") as f:
+        json.dump(tokenizer, f, indent=2)
+        f.write("\n")
+
+
+if __name__ == "__main__":
+    write_gguf(os.path.join(HERE, "model.gguf"))
+    write_tokenizer(os.path.join(HERE, "tokenizer.json"))